@@ -0,0 +1,472 @@
+use crate::cache::{Cache, Entry};
+use crate::config::{SiteConfig, Verbosity};
+use crate::diagnostics::{self, Diagnostic};
+use crate::manifest;
+use crate::render::{Dependencies, Renderer};
+use crate::site::Output;
+use crate::sources::{self, SourceFile, SourceKind, Sources};
+use crate::staging::{self, Staging};
+use crate::vfs::FileSystem;
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// The state of the last build, kept around in watch mode so a change only re-renders
+/// the outputs that depend on it.
+pub struct Build {
+    sources: Sources,
+    /// What each source looked at while being built, keyed by its canonical path. The
+    /// files read include the source itself.
+    dependencies: HashMap<PathBuf, Dependencies>,
+    /// What building each source reported, keyed like `dependencies`.
+    diagnostics: HashMap<PathBuf, Vec<Diagnostic>>,
+    /// Persistent record of earlier builds; `None` when caching is off or nothing is
+    /// being written.
+    cache: Option<Cache>,
+    /// Kept between builds so an update only links again what the last one changed.
+    staging: Option<Staging>,
+    /// Sources built into a staging directory that was thrown away, whose output is
+    /// older than what `dependencies` and `diagnostics` describe. Built again by the
+    /// next update.
+    stale: HashSet<PathBuf>,
+}
+
+impl Build {
+    /// Scans the source directory, builds every page and asset, and removes the outputs
+    /// of sources that are gone.
+    pub fn full(config: &SiteConfig) -> std::io::Result<Build> {
+        let cache = match &config.cache_file {
+            Some(cache_file) if !config.dry_run => Some(Cache::load(cache_file, config)),
+            _ => None,
+        };
+        let mut build = Build {
+            sources: sources::scan(config)?,
+            dependencies: HashMap::new(),
+            diagnostics: HashMap::new(),
+            cache,
+            staging: None,
+            stale: HashSet::new(),
+        };
+        if let Some(cache) = &mut build.cache {
+            cache.retain(
+                &build
+                    .sources
+                    .files
+                    .iter()
+                    .map(|file| file.path.as_path())
+                    .collect(),
+            );
+        }
+        let all: Vec<PathBuf> = build
+            .sources
+            .files
+            .iter()
+            .map(|file| file.canonical.clone())
+            .collect();
+        build.staged(config, &all, true, |build, output_dir| {
+            build.build_sources(config, output_dir, &all)?;
+            if config.dry_run {
+                return Ok(());
+            }
+            let outputs = build
+                .sources
+                .files
+                .iter()
+                .filter_map(|file| file.output_path(Path::new("")))
+                .collect();
+            manifest::update(config, output_dir, outputs)
+        })?;
+        Ok(build)
+    }
+
+    /// Brings the output up to date after the files at `changed` were edited, rendering
+    /// only the pages that read one of them. Falls back to a full build when files or
+    /// directories were added or removed, or an edit turned a page into a partial or
+    /// back.
+    pub fn update(&mut self, config: &SiteConfig, changed: &[PathBuf]) -> std::io::Result<()> {
+        let mut changed_canonical = HashSet::new();
+        for path in changed {
+            let Ok(canonical) = config.source_fs.canonicalize(path) else {
+                return self.rebuild_all(config, "a file was removed");
+            };
+            if config.source_fs.is_dir(&canonical) {
+                if self.sources.contains_dir(&canonical)
+                    || config.source_fs.read_dir(&canonical)?.is_empty()
+                {
+                    continue;
+                }
+                return self.rebuild_all(config, "a directory was added");
+            }
+            if self.is_new_input(config, &canonical) {
+                return self.rebuild_all(config, "a file was added");
+            }
+            if self.sources.refresh(config, &canonical)? {
+                return self.rebuild_all(config, "a page became a partial or back");
+            }
+            changed_canonical.insert(canonical);
+        }
+
+        let affected: Vec<PathBuf> = self
+            .sources
+            .files
+            .iter()
+            .filter(|file| {
+                changed_canonical.contains(&file.canonical)
+                    || self.stale.contains(&file.canonical)
+                    || self
+                        .dependencies
+                        .get(&file.canonical)
+                        .is_some_and(|dependencies| {
+                            !dependencies.read.is_disjoint(&changed_canonical)
+                        })
+            })
+            .map(|file| file.canonical.clone())
+            .collect();
+        if config.verbosity >= Verbosity::Verbose {
+            println!(
+                "Rebuilding {} of {} files",
+                affected.len(),
+                self.sources.files.len()
+            );
+        }
+        self.staged(config, &affected, false, |build, output_dir| {
+            build.build_sources(config, output_dir, &affected)
+        })
+    }
+
+    /// Every page and asset the build publishes, in source order.
+    pub fn outputs(&self, config: &SiteConfig) -> Vec<Output> {
+        self.sources
+            .files
+            .iter()
+            .filter_map(|file| {
+                file.output_path(&config.output_dir).map(|path| Output {
+                    source: file.path.clone(),
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    /// Every file the build read or looked for and didn't find, e.g. templates outside
+    /// the source directory, for watching. Read files are canonical paths.
+    pub fn inputs(&self) -> BTreeSet<PathBuf> {
+        self.dependencies
+            .values()
+            .flat_map(|dependencies| dependencies.read.iter().chain(&dependencies.absent))
+            .cloned()
+            .collect()
+    }
+
+    /// Every diagnostic from the current state of the site, ordered by the source being
+    /// built and then by where the renderer found them, so runs are comparable.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.sources
+            .files
+            .iter()
+            .flat_map(|file| {
+                let scanned = self.sources.diagnostics.get(&file.canonical);
+                let built = self.diagnostics.get(&file.canonical);
+                scanned.into_iter().chain(built).flatten()
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Runs `build_step`, which builds the sources at `canonical_paths`, against a
+    /// staging copy of the output directory, then swaps it in if the site built without
+    /// errors. Otherwise the previous output stays live and those sources are built
+    /// again next time. `full` steps may write or remove any file of the output.
+    fn staged(
+        &mut self,
+        config: &SiteConfig,
+        canonical_paths: &[PathBuf],
+        full: bool,
+        build_step: impl FnOnce(&mut Build, &Path) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        if self.staging.is_none() && !config.dry_run {
+            self.staging = Staging::new(config.output_fs.clone(), &config.output_dir);
+        }
+        let Some(mut staging) = self.staging.take() else {
+            return build_step(self, &config.output_dir);
+        };
+        staging.prepare()?;
+
+        let result = build_step(self, staging.path());
+        let written = match full {
+            true => None,
+            false => Some(
+                canonical_paths
+                    .iter()
+                    .filter_map(|canonical| self.sources.get(canonical))
+                    .filter_map(|file| file.output_path(Path::new("")))
+                    .collect(),
+            ),
+        };
+        if result.is_err()
+            || diagnostics::has_errors(&self.diagnostics(), config.warnings_as_errors)
+        {
+            staging.discard(written)?;
+            self.staging = Some(staging);
+            self.stale.extend(canonical_paths.iter().cloned());
+            if config.verbosity >= Verbosity::Normal {
+                println!("Kept the previous output in {:?}", config.output_dir);
+            }
+            return result;
+        }
+        staging.commit(written)?;
+        self.staging = Some(staging);
+        self.stale.clear();
+        Ok(())
+    }
+
+    /// Starts over with a full build, e.g. after the source directory was replaced.
+    pub fn rebuild_all(&mut self, config: &SiteConfig, reason: &str) -> std::io::Result<()> {
+        if config.verbosity >= Verbosity::Verbose {
+            println!("Rebuilding everything: {}", reason);
+        }
+        // Its staging directory would be set up again by the new build anyway.
+        self.staging = None;
+        *self = Build::full(config)?;
+        Ok(())
+    }
+
+    /// Whether `canonical` is a file this build doesn't know about yet and might read:
+    /// anything but the sources, the templates they read, the output and ignored
+    /// sources.
+    fn is_new_input(&self, config: &SiteConfig, canonical: &Path) -> bool {
+        if self.sources.get(canonical).is_some() || !config.source_fs.is_file(canonical) {
+            return false;
+        }
+        if self
+            .dependencies
+            .values()
+            .any(|dependencies| dependencies.read.contains(canonical))
+        {
+            return false;
+        }
+        if let Ok(output_dir) = config.output_fs.canonicalize(&config.output_dir) {
+            if canonical.starts_with(output_dir) {
+                return false;
+            }
+        }
+        match config.source_fs.canonicalize(&config.source_dir) {
+            Ok(source_dir) => match canonical.strip_prefix(source_dir) {
+                Ok(relative) => !config.is_ignored(&config.source_dir.join(relative)),
+                Err(_) => true,
+            },
+            Err(_) => true,
+        }
+    }
+
+    /// Builds the sources at `canonical_paths` on the worker pool. Results are applied
+    /// and progress printed in source order afterwards, so the output doesn't depend on
+    /// which worker finished first.
+    fn build_sources(
+        &mut self,
+        config: &SiteConfig,
+        output_dir: &Path,
+        canonical_paths: &[PathBuf],
+    ) -> std::io::Result<()> {
+        if let Some(cache) = &mut self.cache {
+            cache.begin();
+        }
+        let outcomes: Vec<Outcome> = canonical_paths
+            .par_iter()
+            .filter(|canonical| !self.sources.collides(canonical))
+            .filter_map(|canonical| self.sources.get(canonical))
+            .map(|source| self.build_source(config, output_dir, source))
+            .collect();
+
+        for outcome in outcomes {
+            if let Some(message) = &outcome.message {
+                println!("{}", message);
+            }
+            if let (Some(cache), Some(entry)) = (&mut self.cache, outcome.cache_entry) {
+                cache.insert(&outcome.path, entry);
+            }
+            self.dependencies
+                .insert(outcome.canonical.clone(), outcome.dependencies);
+            self.diagnostics
+                .insert(outcome.canonical, outcome.diagnostics);
+        }
+
+        if let (Some(cache), Some(cache_file)) = (&self.cache, &config.cache_file) {
+            cache.save(config, cache_file)?;
+        }
+        Ok(())
+    }
+
+    /// Renders, copies or skips one source, writing to `output_dir`. Only reads shared
+    /// state, so any number of these can run at once.
+    fn build_source(&self, config: &SiteConfig, output_dir: &Path, source: &SourceFile) -> Outcome {
+        let mut outcome = Outcome {
+            path: source.path.clone(),
+            canonical: source.canonical.clone(),
+            dependencies: Dependencies::default(),
+            diagnostics: Vec::new(),
+            cache_entry: Some(None),
+            message: None,
+        };
+        let Some(output_path) = source.output_path(output_dir) else {
+            outcome.dependencies.read.insert(source.canonical.clone());
+            if config.verbosity >= Verbosity::Verbose {
+                outcome.message = Some(format!(
+                    "Partial: {:?}, used by {:?}",
+                    source.path,
+                    self.sources.dependents(config, &source.path)
+                ));
+            }
+            return outcome;
+        };
+
+        let unchanged = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.unchanged(config, &source.path, &output_path));
+        if let Some(dependencies) = unchanged {
+            outcome.dependencies = dependencies;
+            outcome.cache_entry = None;
+            if config.verbosity >= Verbosity::Verbose {
+                outcome.message = Some(format!("Unchanged: {:?}", source.path));
+            }
+            return outcome;
+        }
+
+        match process_file(source, &output_path, config, &mut outcome.diagnostics) {
+            Ok((read, output)) => {
+                if outcome.diagnostics.is_empty() {
+                    outcome.cache_entry = Some(
+                        self.cache
+                            .as_ref()
+                            .and_then(|cache| cache.entry(config, &read, &output)),
+                    );
+                }
+                outcome.dependencies = read;
+            }
+            Err(e) => outcome.diagnostics.push(Diagnostic::error(
+                "io",
+                &source.path,
+                format!("Error processing file: {}", e),
+            )),
+        }
+        outcome.dependencies.read.insert(source.canonical.clone());
+        if config.verbosity >= Verbosity::Normal {
+            outcome.message = Some(if config.dry_run {
+                format!("Checked: {:?}", source.path)
+            } else {
+                format!(
+                    "Processed: {:?} -> {:?}",
+                    source.path,
+                    config
+                        .output_dir
+                        .join(output_path.strip_prefix(output_dir).unwrap())
+                )
+            });
+        }
+        outcome
+    }
+}
+
+/// What building one source produced, waiting to be merged into the `Build`.
+struct Outcome {
+    path: PathBuf,
+    canonical: PathBuf,
+    dependencies: Dependencies,
+    diagnostics: Vec<Diagnostic>,
+    /// `Some(None)` drops the source from the cache, `None` leaves its entry alone.
+    cache_entry: Option<Option<Entry>>,
+    /// Progress line to print, if any at this verbosity.
+    message: Option<String>,
+}
+
+/// Renders or copies one page or asset to `output_path`, returning what it looked at
+/// and the bytes that belong in the output.
+fn process_file(
+    source: &SourceFile,
+    output_path: &Path,
+    config: &SiteConfig,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> std::io::Result<(Dependencies, Vec<u8>)> {
+    let (dependencies, output) = render_source(source, config, diagnostics)?;
+    if !config.dry_run {
+        write_if_changed(config.output_fs.as_ref(), output_path, &output)?;
+    }
+    Ok((dependencies, output))
+}
+
+/// What `source` turns into in the output, and what producing it looked at.
+fn render_source(
+    source: &SourceFile,
+    config: &SiteConfig,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> std::io::Result<(Dependencies, Vec<u8>)> {
+    let mut renderer = Renderer::new(config, diagnostics);
+    match source.kind {
+        SourceKind::Page => {
+            let rendered = renderer.render_page(&source.path)?;
+            Ok((renderer.into_dependencies(), rendered.into_bytes()))
+        }
+        SourceKind::Markdown => {
+            let candidates = sources::layout_candidates(config, &source.path);
+            let default_layout = candidates
+                .iter()
+                .find(|path| config.source_fs.is_file(path))
+                .cloned();
+            let rendered = renderer.render_markdown(&source.path, default_layout.as_deref())?;
+            let mut dependencies = renderer.into_dependencies();
+            dependencies.absent.extend(
+                candidates
+                    .into_iter()
+                    .take_while(|path| Some(path) != default_layout.as_ref()),
+            );
+            Ok((dependencies, rendered.into_bytes()))
+        }
+        SourceKind::Asset => {
+            let mut dependencies = Dependencies::default();
+            dependencies.read.insert(source.canonical.clone());
+            Ok((dependencies, config.source_fs.read(&source.path)?))
+        }
+        SourceKind::Partial => Ok((Dependencies::default(), Vec::new())),
+    }
+}
+
+/// Renders the HTML or Markdown page at `path` the way a build would, without writing
+/// anything.
+pub fn render_page(
+    config: &SiteConfig,
+    path: &Path,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> std::io::Result<String> {
+    let kind = match path.extension().and_then(|s| s.to_str()) {
+        Some("html") => SourceKind::Page,
+        Some("md") => SourceKind::Markdown,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:?} is not an HTML or Markdown page", path),
+            ))
+        }
+    };
+    let source = SourceFile {
+        path: path.to_path_buf(),
+        relative: path
+            .strip_prefix(&config.source_dir)
+            .unwrap_or(path)
+            .to_path_buf(),
+        canonical: config.source_fs.canonicalize(path)?,
+        kind,
+    };
+    let (_, output) = render_source(&source, config, diagnostics)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Writes `contents` to `path` unless it already holds exactly that, so unchanged
+/// outputs keep their modification times.
+fn write_if_changed(fs: &dyn FileSystem, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if fs.read(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    fs.create_dir_all(path.parent().unwrap())?;
+    staging::write_file(fs, path, contents)
+}
@@ -0,0 +1,304 @@
+use crate::config::SiteConfig;
+use crate::render::Dependencies;
+use crate::vfs::FileSystem;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// What earlier builds read and wrote for each page, saved between runs so unchanged
+/// pages can be skipped.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Cache {
+    /// Generator version that wrote the cache; any other version starts over.
+    generator: String,
+    /// Hash of the settings that affect output; changing them starts over.
+    config: String,
+    /// Keyed by source path.
+    entries: BTreeMap<PathBuf, Entry>,
+    /// Hashes computed during this build, so shared partials are only read once.
+    #[serde(skip)]
+    hashes: Mutex<HashMap<PathBuf, Option<String>>>,
+}
+
+/// One page or asset as it was last built. Only pages that built without diagnostics
+/// are recorded, so skipping one never hides a warning.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// Hash of every file read, by canonical path.
+    inputs: BTreeMap<PathBuf, String>,
+    /// Files that were looked for and didn't exist.
+    absent: Vec<PathBuf>,
+    /// Hash of what was written to the output path.
+    output: String,
+}
+
+impl Cache {
+    /// Loads the cache at `path`, or starts an empty one if it is missing, unreadable or
+    /// was written by another generator version or with other settings.
+    pub fn load(path: &Path, config: &SiteConfig) -> Cache {
+        let empty = Cache {
+            generator: env!("CARGO_PKG_VERSION").to_string(),
+            config: fingerprint(config),
+            ..Cache::default()
+        };
+        match config
+            .output_fs
+            .read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Cache>(&bytes).ok())
+        {
+            Some(cache) if cache.generator == empty.generator && cache.config == empty.config => {
+                cache
+            }
+            _ => empty,
+        }
+    }
+
+    pub fn save(&self, config: &SiteConfig, path: &Path) -> std::io::Result<()> {
+        config.output_fs.write(
+            path,
+            &serde_json::to_vec(self).map_err(std::io::Error::other)?,
+        )
+    }
+
+    /// Forgets the hashes computed so far, so the next check reads files again.
+    pub fn begin(&mut self) {
+        self.hashes.lock().unwrap().clear();
+    }
+
+    /// What `source` looked at when it was built, if none of it changed and
+    /// `output_path` still holds what was written then.
+    pub fn unchanged(
+        &self,
+        config: &SiteConfig,
+        source: &Path,
+        output_path: &Path,
+    ) -> Option<Dependencies> {
+        let entry = self.entries.get(source)?;
+        if entry
+            .absent
+            .iter()
+            .any(|path| config.source_fs.exists(path))
+        {
+            return None;
+        }
+        for (path, hash) in &entry.inputs {
+            if self.hash_file(config, path).as_ref() != Some(hash) {
+                return None;
+            }
+        }
+        if hash_file(config.output_fs.as_ref(), output_path)? != entry.output {
+            return None;
+        }
+        Some(Dependencies {
+            read: entry.inputs.keys().cloned().collect(),
+            absent: entry.absent.iter().cloned().collect(),
+        })
+    }
+
+    /// Entry for a page that built without diagnostics, after reading `dependencies`
+    /// and producing `output`. `None` if an input can no longer be read.
+    pub fn entry(
+        &self,
+        config: &SiteConfig,
+        dependencies: &Dependencies,
+        output: &[u8],
+    ) -> Option<Entry> {
+        let mut inputs = BTreeMap::new();
+        for path in &dependencies.read {
+            inputs.insert(path.clone(), self.hash_file(config, path)?);
+        }
+        let mut absent: Vec<PathBuf> = dependencies.absent.iter().cloned().collect();
+        absent.sort();
+        Some(Entry {
+            inputs,
+            absent,
+            output: hash(output),
+        })
+    }
+
+    /// Records `entry` for `source`, or forgets `source` if there is none.
+    pub fn insert(&mut self, source: &Path, entry: Option<Entry>) {
+        match entry {
+            Some(entry) => self.entries.insert(source.to_path_buf(), entry),
+            None => self.entries.remove(source),
+        };
+    }
+
+    /// Drops the entries of sources that no longer exist.
+    pub fn retain(&mut self, sources: &HashSet<&Path>) {
+        self.entries
+            .retain(|path, _| sources.contains(path.as_path()));
+    }
+
+    fn hash_file(&self, config: &SiteConfig, path: &Path) -> Option<String> {
+        if let Some(hash) = self.hashes.lock().unwrap().get(path) {
+            return hash.clone();
+        }
+        let hash = hash_file(config.source_fs.as_ref(), path);
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), hash.clone());
+        hash
+    }
+}
+
+pub fn hash(bytes: &[u8]) -> String {
+    blake3::hash(bytes).to_hex().to_string()
+}
+
+fn hash_file(fs: &dyn FileSystem, path: &Path) -> Option<String> {
+    fs.read(path).ok().map(|bytes| hash(&bytes))
+}
+
+/// Hash of every setting that can change what a page renders to.
+fn fingerprint(config: &SiteConfig) -> String {
+    let mut variables: Vec<_> = config.variables.iter().collect();
+    variables.sort();
+    let ignore: Vec<&str> = config
+        .ignore
+        .iter()
+        .map(|pattern| pattern.as_str())
+        .collect();
+    let settings = format!(
+        "{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+        config.source_dir,
+        config.output_dir,
+        config.template_paths,
+        ignore,
+        config.base_url,
+        variables,
+        config.line_endings,
+        config.indent_includes,
+        config.theme.as_ref().map(|theme| &theme.root)
+    );
+    hash(settings.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+    use std::sync::Arc;
+
+    fn config(sources: &Arc<MemoryFs>, output: &Arc<MemoryFs>) -> SiteConfig {
+        SiteConfig {
+            source_dir: PathBuf::from("src"),
+            output_dir: PathBuf::from("out"),
+            source_fs: sources.clone(),
+            output_fs: output.clone(),
+            ..SiteConfig::default()
+        }
+    }
+
+    /// A cache that built `src/index.html` from itself and `src/_nav.html`, after looking
+    /// for `src/nav.html`, into `out/index.html`.
+    fn built(config: &SiteConfig) -> Cache {
+        config.source_fs.create_dir_all(Path::new("src")).unwrap();
+        config.output_fs.create_dir_all(Path::new("out")).unwrap();
+        config
+            .source_fs
+            .write(Path::new("src/index.html"), b"page")
+            .unwrap();
+        config
+            .source_fs
+            .write(Path::new("src/_nav.html"), b"nav")
+            .unwrap();
+        config
+            .output_fs
+            .write(Path::new("out/index.html"), b"html")
+            .unwrap();
+        let mut cache = Cache::load(Path::new(".out.cache.json"), config);
+        let dependencies = Dependencies {
+            read: HashSet::from([
+                PathBuf::from("src/index.html"),
+                PathBuf::from("src/_nav.html"),
+            ]),
+            absent: HashSet::from([PathBuf::from("src/nav.html")]),
+        };
+        let entry = cache.entry(config, &dependencies, b"html");
+        assert!(entry.is_some());
+        cache.insert(Path::new("src/index.html"), entry);
+        cache
+    }
+
+    fn unchanged(cache: &mut Cache, config: &SiteConfig) -> bool {
+        cache.begin();
+        cache
+            .unchanged(
+                config,
+                Path::new("src/index.html"),
+                Path::new("out/index.html"),
+            )
+            .is_some()
+    }
+
+    #[test]
+    fn pages_are_unchanged_until_what_they_read_or_wrote_changes() {
+        let sources = Arc::new(MemoryFs::new());
+        let output = Arc::new(MemoryFs::new());
+        let config = config(&sources, &output);
+        let mut cache = built(&config);
+        let dependencies = cache
+            .unchanged(
+                &config,
+                Path::new("src/index.html"),
+                Path::new("out/index.html"),
+            )
+            .unwrap();
+        assert_eq!(dependencies.read.len(), 2);
+        assert!(dependencies.absent.contains(Path::new("src/nav.html")));
+
+        sources.insert("src/_nav.html", "new nav");
+        assert!(!unchanged(&mut cache, &config));
+        sources.insert("src/_nav.html", "nav");
+        assert!(unchanged(&mut cache, &config));
+
+        sources.insert("src/nav.html", "");
+        assert!(!unchanged(&mut cache, &config));
+        sources.remove_file(Path::new("src/nav.html")).unwrap();
+        assert!(unchanged(&mut cache, &config));
+
+        output.insert("out/index.html", "edited by hand");
+        assert!(!unchanged(&mut cache, &config));
+        output.remove_file(Path::new("out/index.html")).unwrap();
+        assert!(!unchanged(&mut cache, &config));
+    }
+
+    #[test]
+    fn entries_need_every_input_readable() {
+        let sources = Arc::new(MemoryFs::new());
+        let output = Arc::new(MemoryFs::new());
+        let config = config(&sources, &output);
+        let cache = Cache::load(Path::new(".out.cache.json"), &config);
+        let dependencies = Dependencies {
+            read: HashSet::from([PathBuf::from("src/gone.html")]),
+            absent: HashSet::new(),
+        };
+        assert!(cache.entry(&config, &dependencies, b"").is_none());
+    }
+
+    #[test]
+    fn changed_settings_start_over() {
+        let sources = Arc::new(MemoryFs::new());
+        let output = Arc::new(MemoryFs::new());
+        let mut config = config(&sources, &output);
+        let cache_file = Path::new(".out.cache.json");
+        built(&config).save(&config, cache_file).unwrap();
+
+        let mut cache = Cache::load(cache_file, &config);
+        assert!(unchanged(&mut cache, &config));
+
+        config
+            .variables
+            .insert("site_name".to_string(), "Other".to_string());
+        let mut cache = Cache::load(cache_file, &config);
+        assert!(!unchanged(&mut cache, &config));
+
+        output.insert(cache_file, "not json");
+        let mut cache = Cache::load(cache_file, &config);
+        assert!(!unchanged(&mut cache, &config));
+    }
+}
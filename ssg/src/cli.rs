@@ -0,0 +1,170 @@
+use clap::{Args, Parser, Subcommand};
+use generate::{ArchiveFs, LineEndings, MemoryFs, MessageFormat, SiteConfig, Verbosity};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Parser)]
+#[command(
+    name = "generate",
+    version,
+    about = "Builds a static site from HTML templates"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub options: GlobalOptions,
+
+    /// Same as the `watch` subcommand; kept for older scripts.
+    #[arg(long, hide = true)]
+    pub watch: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Build the site once (the default)
+    Build,
+    /// Build, then rebuild whenever a source file changes
+    Watch,
+    /// Build, watch and serve the output directory over HTTP
+    Serve {
+        /// Port to listen on
+        #[arg(long, default_value_t = 8000)]
+        port: u16,
+    },
+    /// Delete the output directory and the build cache
+    Clean,
+    /// Render every page and report errors without writing anything
+    Check,
+    /// Scaffold a new site
+    New {
+        /// Directory to create the site in
+        path: PathBuf,
+    },
+}
+
+#[derive(Args)]
+pub struct GlobalOptions {
+    /// Config file to use instead of ./site.toml
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Read the site, config file included, from a tar, tar.gz or zip archive such as
+    /// the output of `git archive`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub archive: Option<PathBuf>,
+
+    /// Write the built site to a zip archive instead of the output directory (build only)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub zip: Option<PathBuf>,
+
+    /// Source directory, overriding `source_dir`
+    #[arg(long, global = true, value_name = "DIR")]
+    pub src: Option<PathBuf>,
+
+    /// Output directory, overriding `output_dir`
+    #[arg(long, global = true, value_name = "DIR")]
+    pub out: Option<PathBuf>,
+
+    /// Template search path, searched before the configured `template_paths`; may be
+    /// repeated
+    #[arg(long, global = true, value_name = "DIR")]
+    pub templates: Vec<PathBuf>,
+
+    /// Base URL, overriding `base_url`
+    #[arg(long, global = true, value_name = "URL")]
+    pub base_url: Option<String>,
+
+    /// Also print ignored files and the resolved settings
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    pub verbose: bool,
+
+    /// Only print errors
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// How to print errors and warnings
+    #[arg(long, global = true, value_enum, default_value_t = MessageFormat::Human)]
+    pub message_format: MessageFormat,
+
+    /// Exit with a failure status on warnings too
+    #[arg(long, global = true)]
+    pub warnings_as_errors: bool,
+
+    /// Also write errors and warnings to a SARIF report
+    #[arg(long, global = true, value_name = "PATH")]
+    pub sarif: Option<PathBuf>,
+
+    /// Line breaks in rendered pages, overriding `line_endings`
+    #[arg(long, global = true, value_enum, value_name = "STYLE")]
+    pub line_endings: Option<LineEndings>,
+
+    /// Indent included templates like the line their tag is on
+    #[arg(long, global = true)]
+    pub indent_includes: bool,
+
+    /// Delete the output directory and the build cache before building
+    #[arg(long, global = true)]
+    pub clean: bool,
+
+    /// Number of files to build at once [default: one per CPU core]
+    #[arg(short, long, global = true, value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// Render every page, ignoring and not updating the build cache
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+}
+
+impl GlobalOptions {
+    /// Loads the site config and applies the command-line overrides on top of it.
+    pub fn load_config(&self) -> std::io::Result<SiteConfig> {
+        let mut config = match &self.archive {
+            Some(archive) => {
+                SiteConfig::load_from(Arc::new(ArchiveFs::open(archive)?), self.config.as_deref())?
+            }
+            None => SiteConfig::load(self.config.as_deref())?,
+        };
+        if let Some(src) = &self.src {
+            config.source_dir = src.clone();
+        }
+        if let Some(out) = &self.out {
+            config.set_output_dir(out.clone());
+        }
+        config
+            .template_paths
+            .splice(0..0, self.templates.iter().cloned());
+        if let Some(line_endings) = self.line_endings {
+            config.line_endings = line_endings;
+        }
+        if self.indent_includes {
+            config.indent_includes = true;
+        }
+        if let Some(base_url) = &self.base_url {
+            config.set_base_url(base_url.clone());
+        }
+        config.verbosity = if self.message_format == MessageFormat::Json {
+            // Anything but diagnostics would break the JSON stream.
+            Verbosity::Quiet
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else if self.quiet {
+            Verbosity::Quiet
+        } else {
+            Verbosity::Normal
+        };
+        config.message_format = self.message_format;
+        config.warnings_as_errors = self.warnings_as_errors;
+        config.sarif_path = self.sarif.clone();
+        if self.no_cache {
+            config.cache_file = None;
+        }
+        if self.zip.is_some() {
+            config.output_fs = Arc::new(MemoryFs::new());
+            config.cache_file = None;
+        }
+        config.finish()?;
+        Ok(config)
+    }
+}
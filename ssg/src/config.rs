@@ -0,0 +1,291 @@
+use crate::diagnostics::MessageFormat;
+use crate::front_matter::{self, Variables};
+use crate::staging;
+use crate::theme::{self, Theme};
+use crate::vfs::{DiskFs, FileSystem};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Name of the config file looked up in the working directory when `--config` isn't given.
+pub const CONFIG_FILE_NAME: &str = "site.toml";
+
+/// Build settings, read from `site.toml` and then overridden by command-line flags.
+pub struct SiteConfig {
+    /// The file these settings were read from, if any.
+    pub config_path: Option<PathBuf>,
+    pub source_dir: PathBuf,
+    pub output_dir: PathBuf,
+    /// Directories searched, in order, for templates and layouts before the directory of
+    /// the page including them. Templates may only come from these and `source_dir`.
+    pub template_paths: Vec<PathBuf>,
+    /// Globs, relative to `source_dir`, of files and directories left out of the build.
+    pub ignore: Vec<glob::Pattern>,
+    pub base_url: String,
+    /// Site-wide variables, overridden by page front matter. `base_url` is included.
+    pub variables: Variables,
+    pub verbosity: Verbosity,
+    /// Render everything and report errors, but write nothing to `output_dir`.
+    pub dry_run: bool,
+    pub message_format: MessageFormat,
+    /// Fail the build on warnings as well as errors.
+    pub warnings_as_errors: bool,
+    /// Where to write a SARIF report after each build.
+    pub sarif_path: Option<PathBuf>,
+    /// Where to remember what the last build read and wrote, so the next one can skip
+    /// unchanged pages: `.<output>.cache.json` next to the output directory unless the
+    /// config file says otherwise. `None` builds everything every time.
+    pub cache_file: Option<PathBuf>,
+    pub line_endings: LineEndings,
+    /// Indent every line of an included template like the line its tag is on.
+    pub indent_includes: bool,
+    /// A theme directory or archive to build the site on top of, merged in by
+    /// [`SiteConfig::finish`].
+    pub theme_path: Option<PathBuf>,
+    /// The theme once merged in.
+    pub theme: Option<Theme>,
+    /// Where the sources, templates and config file are read from. With a theme, the
+    /// theme's files show through wherever the site has none of its own.
+    pub source_fs: Arc<dyn FileSystem>,
+    /// Where the output, the build cache and the staging directories are written.
+    pub output_fs: Arc<dyn FileSystem>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+/// Line breaks in rendered pages. Copied assets are never changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEndings {
+    /// Keep the line breaks of the sources, byte for byte
+    #[default]
+    Preserve,
+    /// Convert every line break to LF
+    Lf,
+    /// Convert every line break to CRLF
+    Crlf,
+}
+
+/// On-disk layout of `site.toml`. Relative paths are relative to the file itself.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    source_dir: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
+    /// Searched before `template_paths`; kept from when there could only be one.
+    templates_dir: Option<PathBuf>,
+    template_paths: Vec<PathBuf>,
+    cache_file: Option<PathBuf>,
+    /// A theme directory or archive to build the site on top of.
+    theme: Option<PathBuf>,
+    line_endings: LineEndings,
+    indent_includes: bool,
+    ignore: Vec<String>,
+    base_url: String,
+    variables: toml::Table,
+}
+
+impl Default for SiteConfig {
+    /// The settings used without a config file, minus the build cache: `./src` into
+    /// `./generated`.
+    fn default() -> Self {
+        SiteConfig {
+            config_path: None,
+            source_dir: PathBuf::from("./src"),
+            output_dir: PathBuf::from("./generated"),
+            template_paths: Vec::new(),
+            ignore: Vec::new(),
+            base_url: String::new(),
+            variables: Variables::from([("base_url".to_string(), String::new())]),
+            verbosity: Verbosity::Normal,
+            dry_run: false,
+            message_format: MessageFormat::Human,
+            warnings_as_errors: false,
+            sarif_path: None,
+            cache_file: None,
+            line_endings: LineEndings::Preserve,
+            indent_includes: false,
+            theme_path: None,
+            theme: None,
+            source_fs: Arc::new(DiskFs),
+            output_fs: Arc::new(DiskFs),
+        }
+    }
+}
+
+impl SiteConfig {
+    /// Loads `config_path`, or `site.toml` from the working directory if it exists.
+    /// Without a config file the defaults are `./src` and `./generated`. Call
+    /// [`SiteConfig::finish`] after overriding any of it.
+    pub fn load(config_path: Option<&Path>) -> std::io::Result<SiteConfig> {
+        SiteConfig::load_from(Arc::new(DiskFs), config_path)
+    }
+
+    /// Like `load`, reading the config file and then the sources from `source_fs`.
+    pub fn load_from(
+        source_fs: Arc<dyn FileSystem>,
+        config_path: Option<&Path>,
+    ) -> std::io::Result<SiteConfig> {
+        let config_path = match config_path {
+            Some(path) => Some(path.to_path_buf()),
+            None => Some(PathBuf::from(CONFIG_FILE_NAME)).filter(|path| source_fs.is_file(path)),
+        };
+
+        let (file, root) = match &config_path {
+            Some(path) => {
+                let contents = source_fs.read_to_string(path).map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("Error reading {:?}: {}", path, e))
+                })?;
+                let file: ConfigFile = toml::from_str(&contents).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Error parsing {:?}: {}", path, e),
+                    )
+                })?;
+                let root = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                (file, root)
+            }
+            None => (ConfigFile::default(), PathBuf::new()),
+        };
+
+        let mut ignore = Vec::new();
+        for pattern in &file.ignore {
+            ignore.push(glob::Pattern::new(pattern).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid ignore glob {:?}: {}", pattern, e),
+                )
+            })?);
+        }
+
+        let mut variables = Variables::new();
+        for (key, value) in &file.variables {
+            front_matter::flatten_toml(key, value, &mut variables);
+        }
+
+        let output_dir = resolve(&root, file.output_dir, "./generated");
+        let mut config = SiteConfig {
+            config_path,
+            source_dir: resolve(&root, file.source_dir, "./src"),
+            cache_file: match file.cache_file {
+                Some(cache_file) => Some(root.join(cache_file)),
+                None => default_cache_file(&output_dir),
+            },
+            output_dir,
+            template_paths: file
+                .templates_dir
+                .into_iter()
+                .chain(file.template_paths)
+                .map(|dir| root.join(dir))
+                .collect(),
+            ignore,
+            variables,
+            line_endings: file.line_endings,
+            indent_includes: file.indent_includes,
+            theme_path: file.theme.map(|path| root.join(path)),
+            source_fs,
+            ..SiteConfig::default()
+        };
+        config.set_base_url(file.base_url);
+        Ok(config)
+    }
+
+    /// Applies what depends on the other settings, once every override is in: refuses
+    /// an output directory that overlaps the sources, merges the theme beneath
+    /// `source_dir` and checks the variables it requires. The theme is only merged the
+    /// first time, so change `source_dir` and `source_fs` before calling it.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        for dir in std::iter::once(&self.source_dir).chain(&self.template_paths) {
+            if self.overlaps_output_dir(dir) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Refusing to build into {:?}: it overlaps {:?}, which the build reads",
+                        self.output_dir, dir
+                    ),
+                ));
+            }
+        }
+        if let (Some(theme_path), None) = (self.theme_path.clone(), &self.theme) {
+            theme::apply(self, &theme_path)?;
+        }
+        Ok(())
+    }
+
+    /// Builds into `dir` instead, moving the build cache along if it is kept in its
+    /// default place next to the output.
+    pub fn set_output_dir(&mut self, dir: PathBuf) {
+        if self.cache_file.is_some() && self.cache_file == default_cache_file(&self.output_dir) {
+            self.cache_file = default_cache_file(&dir);
+        }
+        self.output_dir = dir;
+    }
+
+    pub fn set_base_url(&mut self, base_url: String) {
+        self.variables
+            .insert("base_url".to_string(), base_url.clone());
+        self.base_url = base_url;
+    }
+
+    /// Whether `dir` is the output directory, or holds it or is held by it, so a build
+    /// would write over it or swap it out with the output.
+    pub fn overlaps_output_dir(&self, dir: &Path) -> bool {
+        let fs = self.output_fs.as_ref();
+        match (absolute(fs, &self.output_dir), absolute(fs, dir)) {
+            (Some(output_dir), Some(dir)) => {
+                output_dir.starts_with(&dir) || dir.starts_with(&output_dir)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `path`, somewhere under `source_dir`, matches one of the ignore globs.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.source_dir).unwrap_or(path);
+        self.ignore
+            .iter()
+            .any(|pattern| pattern.matches_path(relative))
+    }
+}
+
+/// `path` with its longest existing ancestor canonicalized, so it can be compared with
+/// other paths before it exists.
+fn absolute(fs: &dyn FileSystem, path: &Path) -> Option<PathBuf> {
+    let mut missing = Vec::new();
+    let mut current = path;
+    loop {
+        let existing = match current.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => current,
+        };
+        if let Ok(canonical) = fs.canonicalize(existing) {
+            return Some(
+                missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |path, name| path.join(name)),
+            );
+        }
+        missing.push(current.file_name()?);
+        current = current.parent()?;
+    }
+}
+
+/// `.<name>.cache.json` next to `output_dir`, like the directories builds stage in, so
+/// the cache follows the output wherever it is configured to go.
+fn default_cache_file(output_dir: &Path) -> Option<PathBuf> {
+    staging::sibling(output_dir, "cache.json")
+}
+
+fn resolve(root: &Path, path: Option<PathBuf>, default: &str) -> PathBuf {
+    match path {
+        Some(path) => root.join(path),
+        None if root.as_os_str().is_empty() => PathBuf::from(default),
+        None => root.join(default),
+    }
+}
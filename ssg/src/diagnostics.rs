@@ -0,0 +1,232 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found while building, pointing at the file (and where known, the line and
+/// column) that caused it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: PathBuf,
+    /// One-based line number.
+    pub line: Option<usize>,
+    /// One-based column, counted in characters.
+    pub column: Option<usize>,
+    /// Stable kebab-case identifier, e.g. `missing-template`.
+    pub code: &'static str,
+    pub message: String,
+    /// Files being expanded when the problem was found, outermost page first.
+    pub include_chain: Vec<PathBuf>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, file: &Path, message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Error, code, file, message.into())
+    }
+
+    pub fn warning(code: &'static str, file: &Path, message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Warning, code, file, message.into())
+    }
+
+    fn new(severity: Severity, code: &'static str, file: &Path, message: String) -> Self {
+        Diagnostic {
+            severity,
+            file: file.to_path_buf(),
+            line: None,
+            column: None,
+            code,
+            message,
+            include_chain: Vec::new(),
+        }
+    }
+
+    /// Sets the one-based line and column.
+    pub fn at(mut self, line: usize, column: usize) -> Self {
+        self.line = Some(line);
+        self.column = Some(column);
+        self
+    }
+
+    pub fn with_include_chain(mut self, include_chain: &[PathBuf]) -> Self {
+        self.include_chain = include_chain.to_vec();
+        self
+    }
+
+    /// `file:line:column`, leaving out whatever isn't known.
+    pub fn location(&self) -> String {
+        let mut location = self.file.display().to_string();
+        if let Some(line) = self.line {
+            location.push_str(&format!(":{}", line));
+            if let Some(column) = self.column {
+                location.push_str(&format!(":{}", column));
+            }
+        }
+        location
+    }
+}
+
+/// How diagnostics are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MessageFormat {
+    /// Colored, with the include chain on its own line
+    Human,
+    /// One uncolored `file:line:column: severity[code]: message` line each
+    Short,
+    /// One JSON object per line; other output is suppressed
+    Json,
+}
+
+pub fn render(diagnostic: &Diagnostic, format: MessageFormat) -> String {
+    match format {
+        MessageFormat::Human => render_human(diagnostic),
+        MessageFormat::Short => render_short(diagnostic),
+        MessageFormat::Json => serde_json::to_string(diagnostic).unwrap(),
+    }
+}
+
+fn render_human(diagnostic: &Diagnostic) -> String {
+    let color = match diagnostic.severity {
+        Severity::Warning => "\x1b[33m",
+        Severity::Error => "\x1b[31m",
+    };
+    let mut rendered = format!(
+        "{}{}[{}]\x1b[0m: {}\n    at {}",
+        color,
+        diagnostic.severity,
+        diagnostic.code,
+        diagnostic.message,
+        diagnostic.location()
+    );
+    if diagnostic.include_chain.len() > 1 {
+        let chain: Vec<String> = diagnostic
+            .include_chain
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        rendered.push_str(&format!("\n    included via {}", chain.join(" -> ")));
+    }
+    rendered
+}
+
+fn render_short(diagnostic: &Diagnostic) -> String {
+    format!(
+        "{}: {}[{}]: {}",
+        diagnostic.location(),
+        diagnostic.severity,
+        diagnostic.code,
+        diagnostic.message
+    )
+}
+
+/// Writes `diagnostics` to `path` as a SARIF 2.1.0 log, for code scanning tools.
+pub fn write_sarif(diagnostics: &[Diagnostic], path: &Path) -> std::io::Result<()> {
+    let mut rule_ids: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+    rule_ids.sort_unstable();
+    rule_ids.dedup();
+    let rules: Vec<serde_json::Value> = rule_ids
+        .iter()
+        .map(|id| serde_json::json!({ "id": id }))
+        .collect();
+
+    let results: Vec<serde_json::Value> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let mut region = serde_json::Map::new();
+            if let Some(line) = diagnostic.line {
+                region.insert("startLine".to_string(), line.into());
+            }
+            if let Some(column) = diagnostic.column {
+                region.insert("startColumn".to_string(), column.into());
+            }
+            let mut physical_location = serde_json::json!({
+                "artifactLocation": { "uri": sarif_uri(&diagnostic.file) },
+            });
+            if !region.is_empty() {
+                physical_location["region"] = region.into();
+            }
+
+            let related_locations: Vec<serde_json::Value> = diagnostic
+                .include_chain
+                .iter()
+                .enumerate()
+                .map(|(id, path)| {
+                    serde_json::json!({
+                        "id": id,
+                        "message": { "text": "included from here" },
+                        "physicalLocation": {
+                            "artifactLocation": { "uri": sarif_uri(path) },
+                        },
+                    })
+                })
+                .collect();
+
+            serde_json::json!({
+                "ruleId": diagnostic.code,
+                "level": diagnostic.severity.to_string(),
+                "message": { "text": diagnostic.message },
+                "locations": [{ "physicalLocation": physical_location }],
+                "relatedLocations": related_locations,
+            })
+        })
+        .collect();
+
+    let log = serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    });
+    fs::write(path, serde_json::to_string_pretty(&log).unwrap())
+}
+
+/// Forward-slashed path as SARIF consumers expect in `artifactLocation.uri`: relative
+/// paths stay relative, absolute ones become `file://` URIs.
+fn sarif_uri(path: &Path) -> String {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    let parts: Vec<_> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            Component::ParentDir => Some("..".into()),
+            _ => None,
+        })
+        .collect();
+    if path.is_absolute() {
+        format!("file:///{}", parts.join("/"))
+    } else {
+        parts.join("/")
+    }
+}
+
+/// Whether `diagnostics` should fail the build.
+pub fn has_errors(diagnostics: &[Diagnostic], warnings_as_errors: bool) -> bool {
+    diagnostics.iter().any(|diagnostic| {
+        diagnostic.severity == Severity::Error
+            || (warnings_as_errors && diagnostic.severity == Severity::Warning)
+    })
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+/// Variables available to `{{ name }}` placeholders. Nested tables are flattened into
+/// dotted keys, so `author = { name = "Data" }` is reachable as `{{ author.name }}`.
+pub type Variables = HashMap<String, String>;
+
+#[derive(Default)]
+pub struct FrontMatter {
+    pub variables: Variables,
+    /// Arguments a template requires from every `<!-- template: -->` tag that includes it,
+    /// declared as `params = ["title", "id"]`.
+    pub params: Vec<String>,
+    /// Number of lines the front matter block occupies, delimiters included.
+    pub line_count: usize,
+}
+
+/// Splits an optional front matter block off the top of `lines`. TOML front matter is
+/// fenced with `+++`, YAML with `---`. Returns `Ok(None)` when the file has none.
+pub fn parse(lines: &[&str]) -> Result<Option<FrontMatter>, String> {
+    let delimiter = match lines.first().map(|line| line.trim_end()) {
+        Some("+++") => "+++",
+        Some("---") => "---",
+        _ => return Ok(None),
+    };
+    let Some(end) = lines[1..]
+        .iter()
+        .position(|line| line.trim_end() == delimiter)
+    else {
+        return Err(format!(
+            "front matter opened with {} is never closed",
+            delimiter
+        ));
+    };
+    let body = lines[1..=end].join("\n");
+
+    let mut variables = Variables::new();
+    let mut params = Vec::new();
+    if delimiter == "+++" {
+        let mut table: toml::Table = toml::from_str(&body).map_err(|e| e.to_string())?;
+        match table.remove("params") {
+            Some(toml::Value::Array(items)) => {
+                for item in items {
+                    match item {
+                        toml::Value::String(name) => params.push(name),
+                        _ => return Err("params must be a list of names".to_string()),
+                    }
+                }
+            }
+            Some(_) => return Err("params must be a list of names".to_string()),
+            None => {}
+        }
+        for (key, value) in table {
+            flatten_toml(&key, &value, &mut variables);
+        }
+    } else {
+        let value: serde_yaml::Value = serde_yaml::from_str(&body).map_err(|e| e.to_string())?;
+        match value {
+            serde_yaml::Value::Mapping(mut mapping) => {
+                match mapping.remove("params") {
+                    Some(serde_yaml::Value::Sequence(items)) => {
+                        for item in items {
+                            match item {
+                                serde_yaml::Value::String(name) => params.push(name),
+                                _ => return Err("params must be a list of names".to_string()),
+                            }
+                        }
+                    }
+                    Some(_) => return Err("params must be a list of names".to_string()),
+                    None => {}
+                }
+                for (key, value) in mapping {
+                    flatten_yaml(&yaml_scalar(&key), &value, &mut variables);
+                }
+            }
+            serde_yaml::Value::Null => {}
+            _ => return Err("YAML front matter must be a mapping".to_string()),
+        }
+    }
+
+    Ok(Some(FrontMatter {
+        variables,
+        params,
+        line_count: end + 2,
+    }))
+}
+
+pub fn flatten_toml(key: &str, value: &toml::Value, variables: &mut Variables) {
+    match value {
+        toml::Value::Table(table) => {
+            for (child_key, child) in table {
+                flatten_toml(&format!("{}.{}", key, child_key), child, variables);
+            }
+        }
+        toml::Value::String(s) => {
+            variables.insert(key.to_string(), s.clone());
+        }
+        toml::Value::Array(items) => {
+            let items: Vec<String> = items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect();
+            variables.insert(key.to_string(), items.join(", "));
+        }
+        other => {
+            variables.insert(key.to_string(), other.to_string());
+        }
+    }
+}
+
+fn flatten_yaml(key: &str, value: &serde_yaml::Value, variables: &mut Variables) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            for (child_key, child) in mapping {
+                flatten_yaml(
+                    &format!("{}.{}", key, yaml_scalar(child_key)),
+                    child,
+                    variables,
+                );
+            }
+        }
+        serde_yaml::Value::Sequence(items) => {
+            let items: Vec<String> = items.iter().map(yaml_scalar).collect();
+            variables.insert(key.to_string(), items.join(", "));
+        }
+        other => {
+            variables.insert(key.to_string(), yaml_scalar(other));
+        }
+    }
+}
+
+fn yaml_scalar(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::Null => String::new(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other)
+            .unwrap_or_default()
+            .trim_end()
+            .to_string(),
+    }
+}
@@ -0,0 +1,29 @@
+//! A static site generator that assembles HTML pages from templates and Markdown.
+//!
+//! The `generate` binary is a thin command line over this crate. Programs that want to
+//! build a site themselves configure a [`Site`] with a [`Builder`], or render a single
+//! page in memory with [`Site::render_page`].
+
+mod build;
+mod cache;
+pub mod config;
+pub mod diagnostics;
+mod front_matter;
+mod manifest;
+mod markdown;
+mod render;
+pub mod scaffold;
+pub mod serve;
+mod site;
+mod sources;
+mod staging;
+mod template;
+pub mod theme;
+pub mod vfs;
+pub mod watch;
+
+pub use config::{LineEndings, SiteConfig, Verbosity};
+pub use diagnostics::{Diagnostic, MessageFormat, Severity};
+pub use site::{BuildReport, Builder, Output, RenderedPage, Site};
+pub use theme::Theme;
+pub use vfs::{ArchiveFs, DiskFs, FileSystem, MemoryFs, OverlayFs};
@@ -0,0 +1,131 @@
+use crate::config::{SiteConfig, Verbosity};
+use crate::staging;
+use crate::vfs::FileSystem;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Written to the output directory after every build. Lists the files the build wrote,
+/// relative to the output directory, and marks the directory as one it may delete.
+pub const MANIFEST_FILE_NAME: &str = ".generate-manifest.json";
+
+/// Whether `output_dir` holds a manifest, i.e. was created by a build.
+pub fn exists(fs: &dyn FileSystem, output_dir: &Path) -> bool {
+    fs.is_file(&output_dir.join(MANIFEST_FILE_NAME))
+}
+
+/// Removes the files the previous build wrote to `output_dir` that aren't in `outputs`
+/// any more, along with directories left empty, then records `outputs` as the new
+/// manifest. Files the build never wrote are left alone.
+pub fn update(
+    config: &SiteConfig,
+    output_dir: &Path,
+    outputs: BTreeSet<PathBuf>,
+) -> std::io::Result<()> {
+    let manifest_path = output_dir.join(MANIFEST_FILE_NAME);
+    let fs = config.output_fs.as_ref();
+    let previous: BTreeSet<PathBuf> = fs
+        .read(&manifest_path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default();
+
+    for stale in previous.difference(&outputs) {
+        let path = output_dir.join(stale);
+        match fs.remove_file(&path) {
+            Ok(()) => {
+                if config.verbosity >= Verbosity::Normal {
+                    println!("Removed: {:?}", config.output_dir.join(stale));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        remove_empty_parents(fs, output_dir, &path);
+    }
+
+    fs.create_dir_all(output_dir)?;
+    staging::write_file(
+        fs,
+        &manifest_path,
+        &serde_json::to_vec_pretty(&outputs).map_err(std::io::Error::other)?,
+    )
+}
+
+/// Removes the directories above `path` that are now empty, stopping at `output_dir`.
+fn remove_empty_parents(fs: &dyn FileSystem, output_dir: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == output_dir || fs.remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+    use std::sync::Arc;
+
+    fn outputs(paths: &[&str]) -> BTreeSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn prunes_what_the_last_build_wrote_and_nothing_else() {
+        let fs = Arc::new(MemoryFs::new());
+        let config = SiteConfig {
+            output_dir: PathBuf::from("out"),
+            verbosity: Verbosity::Quiet,
+            output_fs: fs.clone(),
+            ..SiteConfig::default()
+        };
+        let previous = [
+            "index.html",
+            "blog/post.html",
+            "docs/old/page.html",
+            "notes/draft.html",
+        ];
+        for path in previous {
+            fs.insert(Path::new("out").join(path), "");
+        }
+        fs.insert("out/notes/mine.txt", "");
+        fs.insert("out/mine.txt", "");
+        update(&config, Path::new("out"), outputs(&previous)).unwrap();
+
+        update(&config, Path::new("out"), outputs(&["index.html"])).unwrap();
+        assert!(fs.exists(Path::new("out/index.html")));
+        assert!(!fs.exists(Path::new("out/blog")));
+        assert!(!fs.exists(Path::new("out/docs")));
+        assert!(!fs.exists(Path::new("out/notes/draft.html")));
+        assert!(fs.exists(Path::new("out/notes/mine.txt")));
+        assert!(fs.exists(Path::new("out/mine.txt")));
+        let manifest: BTreeSet<PathBuf> =
+            serde_json::from_slice(&fs.read(&Path::new("out").join(MANIFEST_FILE_NAME)).unwrap())
+                .unwrap();
+        assert_eq!(manifest, outputs(&["index.html"]));
+    }
+
+    #[test]
+    fn files_already_gone_are_skipped() {
+        let fs = Arc::new(MemoryFs::new());
+        let config = SiteConfig {
+            output_dir: PathBuf::from("out"),
+            verbosity: Verbosity::Quiet,
+            output_fs: fs.clone(),
+            ..SiteConfig::default()
+        };
+        fs.insert("out/a/b.html", "");
+        update(
+            &config,
+            Path::new("out"),
+            outputs(&["a/b.html", "a/c.html"]),
+        )
+        .unwrap();
+
+        update(&config, Path::new("out"), BTreeSet::new()).unwrap();
+        assert!(!fs.exists(Path::new("out/a")));
+        assert!(exists(fs.as_ref(), Path::new("out")));
+    }
+}
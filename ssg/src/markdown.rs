@@ -0,0 +1,67 @@
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd};
+use std::collections::HashSet;
+
+/// Converts CommonMark with tables, footnotes, task lists and strikethrough to HTML.
+/// Headings without an explicit `{#id}` get an anchor id slugged from their text.
+pub fn to_html(source: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    let mut events: Vec<Event> = Parser::new_ext(source, options).collect();
+
+    let mut used_ids = HashSet::new();
+    for event in &events {
+        if let Event::Start(Tag::Heading { id: Some(id), .. }) = event {
+            used_ids.insert(id.to_string());
+        }
+    }
+
+    for index in 0..events.len() {
+        if !matches!(events[index], Event::Start(Tag::Heading { id: None, .. })) {
+            continue;
+        }
+        let mut text = String::new();
+        for event in &events[index + 1..] {
+            match event {
+                Event::End(TagEnd::Heading(_)) => break,
+                Event::Text(t) | Event::Code(t) => text.push_str(t),
+                _ => {}
+            }
+        }
+        let anchor = unique_slug(&text, &mut used_ids);
+        if let Event::Start(Tag::Heading { id, .. }) = &mut events[index] {
+            *id = Some(CowStr::from(anchor));
+        }
+    }
+
+    let mut output = String::new();
+    html::push_html(&mut output, events.into_iter());
+    output
+}
+
+/// Lowercases `text` and joins its alphanumeric runs with dashes, appending `-1`, `-2`...
+/// when the slug is already taken on the page.
+fn unique_slug(text: &str, used_ids: &mut HashSet<String>) -> String {
+    let slug = text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let slug = if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug
+    };
+
+    let mut candidate = slug.clone();
+    let mut suffix = 1;
+    while used_ids.contains(&candidate) {
+        candidate = format!("{}-{}", slug, suffix);
+        suffix += 1;
+    }
+    used_ids.insert(candidate.clone());
+    candidate
+}
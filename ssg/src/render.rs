@@ -0,0 +1,911 @@
+use crate::config::{LineEndings, SiteConfig};
+use crate::diagnostics::Diagnostic;
+use crate::front_matter::{self, FrontMatter, Variables};
+use crate::markdown;
+use crate::template::{self, Source, Token, TokenKind};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// What a name in a tag or front matter refers to, for error messages.
+#[derive(Clone, Copy)]
+enum Reference {
+    Layout,
+    Template,
+}
+
+const BYTE_ORDER_MARK: char = '\u{feff}';
+
+/// A `{{ name }}` placeholder, or `\{{ name }}` standing for the placeholder itself.
+static VARIABLE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\\)?\{\{\s*([A-Za-z0-9_.-]+)\s*\}\}").unwrap());
+
+/// An opening or closing `<pre>` or `<textarea>` tag.
+static RAW_TAG_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)<(/?)(?:pre|textarea)\b").unwrap());
+
+/// One `name="value"` argument of an include tag.
+static ARGUMENT_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^\s+([A-Za-z_][A-Za-z0-9_-]*)="([^"]*)""#).unwrap());
+
+/// Where a template is being included from, and the arguments its tag passed.
+struct Include<'b> {
+    caller: &'b Path,
+    line: usize,
+    column: usize,
+    arguments: &'b Variables,
+}
+
+/// Files a render looked at, so a change to any of them can be traced back to the pages
+/// that need rendering again.
+#[derive(Default)]
+pub struct Dependencies {
+    /// Canonical paths of every file read.
+    pub read: HashSet<PathBuf>,
+    /// Paths that were looked for and not found, e.g. a template next to the page when
+    /// the one in the templates directory was used. Creating one changes the output.
+    pub absent: HashSet<PathBuf>,
+}
+
+/// Expands template tags, layouts and variables for a single page.
+pub struct Renderer<'a> {
+    /// Paths of every file currently being expanded, outermost first.
+    include_chain: Vec<PathBuf>,
+    config: &'a SiteConfig,
+    diagnostics: &'a mut Vec<Diagnostic>,
+    dependencies: Dependencies,
+    /// Whether the page being rendered starts with a byte order mark. Marks are
+    /// stripped from every file read and put back on the rendered page only.
+    byte_order_mark: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(config: &'a SiteConfig, diagnostics: &'a mut Vec<Diagnostic>) -> Self {
+        Renderer {
+            include_chain: Vec::new(),
+            config,
+            diagnostics,
+            dependencies: Dependencies::default(),
+            byte_order_mark: false,
+        }
+    }
+
+    /// Everything the renders so far have looked at.
+    pub fn into_dependencies(self) -> Dependencies {
+        self.dependencies
+    }
+
+    /// Renders the page at `input_path` with no inherited blocks or variables.
+    pub fn render_page(&mut self, input_path: &Path) -> std::io::Result<String> {
+        self.include_chain = vec![input_path.to_path_buf()];
+        let rendered = self.render_html(input_path, &HashMap::new(), &Variables::new(), None)?;
+        Ok(self.finish(rendered))
+    }
+
+    /// Renders the Markdown page at `input_path` and places it in the `content` block of
+    /// its layout: the one named by a `layout` front matter key, else `default_layout`.
+    /// Without either the bare HTML is returned.
+    pub fn render_markdown(
+        &mut self,
+        input_path: &Path,
+        default_layout: Option<&Path>,
+    ) -> std::io::Result<String> {
+        self.include_chain = vec![input_path.to_path_buf()];
+        let rendered = self.expand_markdown(input_path, default_layout)?;
+        Ok(self.finish(rendered))
+    }
+
+    /// Template tags and variables in the body are expanded before it is converted, so
+    /// included HTML ends up in the page like any other HTML in Markdown.
+    fn expand_markdown(
+        &mut self,
+        input_path: &Path,
+        default_layout: Option<&Path>,
+    ) -> std::io::Result<String> {
+        let source = self.read_source(input_path)?;
+        let lines: Vec<&str> = source.lines().collect();
+        let front_matter = self.parse_front_matter(input_path, &lines);
+        let mut variables = self.config.variables.clone();
+        variables.extend(front_matter.variables.clone());
+        let (tokens, errors) = template::tokenize(
+            &source,
+            template::line_offset(&source, front_matter.line_count),
+        );
+        let source = Source::new(&source);
+        self.report_tokenizer_errors(input_path, &source, errors);
+        let body = self.render_tokens(input_path, &source, &tokens, &HashMap::new(), &variables)?;
+        let content = markdown::to_html(&body);
+
+        let layout_path = match front_matter.variables.get("layout") {
+            Some(layout_name) => {
+                match self.resolve(input_path, layout_name, Reference::Layout, None) {
+                    Some(layout_path) => layout_path,
+                    None => return Ok(content),
+                }
+            }
+            None => match default_layout {
+                Some(layout_path) => layout_path.to_path_buf(),
+                None => return Ok(content),
+            },
+        };
+
+        let blocks = HashMap::from([("content".to_string(), content)]);
+        self.include_chain.push(layout_path.clone());
+        let rendered = self.render_html(&layout_path, &blocks, &variables, None)?;
+        self.include_chain.pop();
+        Ok(rendered)
+    }
+
+    /// Like `resolve_template`, remembering the places that were looked at and came up
+    /// empty so creating one of them later is noticed. Templates outside the source
+    /// directory and the template search paths are refused. Failures are reported at
+    /// `at`, the line and column of the reference if it has one.
+    fn resolve(
+        &mut self,
+        input_path: &Path,
+        name: &str,
+        reference: Reference,
+        at: Option<(usize, usize)>,
+    ) -> Option<PathBuf> {
+        let (code, kind) = match reference {
+            Reference::Layout => ("missing-layout", "Layout"),
+            Reference::Template => ("missing-template", "Template"),
+        };
+        let mut candidates = template_candidates(self.config, input_path, name);
+        let found = candidates
+            .iter()
+            .position(|path| self.config.source_fs.exists(path));
+        self.dependencies
+            .absent
+            .extend(candidates.drain(..found.unwrap_or(candidates.len())));
+        let diagnostic = if found.is_none() {
+            Diagnostic::error(code, input_path, format!("{} {} not found", kind, name))
+        } else if !is_within_roots(self.config, &candidates[0]) {
+            Diagnostic::error(
+                "template-outside-roots",
+                input_path,
+                format!(
+                    "{} {} resolves to {}, outside the source directory and template paths",
+                    kind,
+                    name,
+                    candidates[0].display()
+                ),
+            )
+        } else {
+            return Some(candidates.swap_remove(0));
+        };
+        self.report(match at {
+            Some((line, column)) => diagnostic.at(line, column),
+            None => diagnostic,
+        });
+        None
+    }
+
+    /// Applies the configured line endings to a rendered page and puts its byte order
+    /// mark back.
+    fn finish(&self, rendered: String) -> String {
+        let rendered = match self.config.line_endings {
+            LineEndings::Preserve => rendered,
+            LineEndings::Lf => rendered.replace("\r\n", "\n"),
+            LineEndings::Crlf => rendered.replace("\r\n", "\n").replace('\n', "\r\n"),
+        };
+        match self.byte_order_mark {
+            true => format!("{}{}", BYTE_ORDER_MARK, rendered),
+            false => rendered,
+        }
+    }
+
+    fn read_source(&mut self, path: &Path) -> std::io::Result<String> {
+        let mut source = self.config.source_fs.read_to_string(path)?;
+        let byte_order_mark = source.starts_with(BYTE_ORDER_MARK);
+        if byte_order_mark {
+            source.drain(..BYTE_ORDER_MARK.len_utf8());
+        }
+        if self.include_chain.len() == 1 {
+            self.byte_order_mark = byte_order_mark;
+        }
+        self.dependencies.read.insert(
+            self.config
+                .source_fs
+                .canonicalize(path)
+                .unwrap_or_else(|_| path.to_path_buf()),
+        );
+        Ok(source)
+    }
+
+    /// Parses the front matter of `input_path`, reporting malformed blocks and treating
+    /// them as absent.
+    fn parse_front_matter(&mut self, input_path: &Path, lines: &[&str]) -> FrontMatter {
+        match front_matter::parse(lines) {
+            Ok(front_matter) => front_matter.unwrap_or_default(),
+            Err(e) => {
+                self.report(Diagnostic::error(
+                    "invalid-front-matter",
+                    input_path,
+                    format!("Invalid front matter: {}", e),
+                ));
+                FrontMatter::default()
+            }
+        }
+    }
+
+    fn report_tokenizer_errors(
+        &mut self,
+        input_path: &Path,
+        source: &Source,
+        errors: Vec<template::Error>,
+    ) {
+        for error in errors {
+            let (line, column) = source.position(error.offset);
+            self.report(Diagnostic::error(error.code, input_path, error.message).at(line, column));
+        }
+    }
+
+    /// Records `diagnostic` along with the include chain it was found under.
+    fn report(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics
+            .push(diagnostic.with_include_chain(&self.include_chain));
+    }
+
+    /// Renders `input_path`, expanding template tags recursively and resolving layout
+    /// inheritance. `blocks` holds rendered block overrides from descendant pages and
+    /// `inherited` the variables of the including page, which win over the file's own
+    /// front matter. Arguments passed by an `include` tag win over both.
+    fn render_html(
+        &mut self,
+        input_path: &Path,
+        blocks: &HashMap<String, String>,
+        inherited: &Variables,
+        include: Option<Include>,
+    ) -> std::io::Result<String> {
+        let source = self.read_source(input_path)?;
+        let lines: Vec<&str> = source.lines().collect();
+
+        let front_matter = self.parse_front_matter(input_path, &lines);
+        let mut variables = self.config.variables.clone();
+        variables.extend(front_matter.variables);
+        let params = front_matter.params;
+        let (tokens, errors) = template::tokenize(
+            &source,
+            template::line_offset(&source, front_matter.line_count),
+        );
+        let source = Source::new(&source);
+        self.report_tokenizer_errors(input_path, &source, errors);
+        variables.extend(inherited.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        if let Some(include) = include {
+            let missing: Vec<&str> = params
+                .iter()
+                .filter(|param| !include.arguments.contains_key(*param))
+                .map(|param| param.as_str())
+                .collect();
+            if !missing.is_empty() {
+                self.report(
+                    Diagnostic::error(
+                        "missing-argument",
+                        include.caller,
+                        format!(
+                            "Template {} is missing required argument(s) {}",
+                            input_path.display(),
+                            missing.join(", ")
+                        ),
+                    )
+                    .at(include.line, include.column),
+                );
+                return Ok(String::new());
+            }
+            // A template that declares its params takes nothing else, so any other
+            // argument is most likely a misspelling of one of them.
+            if !params.is_empty() {
+                let mut unused: Vec<&str> = include
+                    .arguments
+                    .keys()
+                    .filter(|name| !params.contains(*name))
+                    .map(|name| name.as_str())
+                    .collect();
+                unused.sort_unstable();
+                if !unused.is_empty() {
+                    self.report(
+                        Diagnostic::warning(
+                            "unused-argument",
+                            include.caller,
+                            format!(
+                                "Template {} takes no argument(s) {}",
+                                input_path.display(),
+                                unused.join(", ")
+                            ),
+                        )
+                        .at(include.line, include.column),
+                    );
+                }
+            }
+            variables.extend(
+                include
+                    .arguments
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+
+        let extends = tokens
+            .iter()
+            .position(|token| {
+                token.kind != TokenKind::Text
+                    || !source.text[token.start..token.end].trim().is_empty()
+            })
+            .and_then(|index| match tokens[index].kind {
+                TokenKind::Extends { name } => Some((index, name)),
+                _ => None,
+            });
+
+        if let Some((index, layout_name)) = extends {
+            let (line, column) = source.position(tokens[index].offset);
+            match self.resolve(
+                input_path,
+                layout_name,
+                Reference::Layout,
+                Some((line, column)),
+            ) {
+                Some(layout_path)
+                    if is_in_include_chain(self.config, &self.include_chain, &layout_path)? =>
+                {
+                    self.report(
+                        Diagnostic::error(
+                            "layout-cycle",
+                            input_path,
+                            format!(
+                                "Layout cycle detected: {}",
+                                format_include_chain(&self.include_chain, &layout_path)
+                            ),
+                        )
+                        .at(line, column),
+                    );
+                }
+                Some(layout_path) => {
+                    // Blocks filled by descendants win over the ones defined here.
+                    let mut merged_blocks = self.collect_blocks(
+                        input_path,
+                        &source,
+                        &tokens[index + 1..],
+                        blocks,
+                        &variables,
+                    )?;
+                    merged_blocks.extend(blocks.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+                    self.include_chain.push(layout_path.clone());
+                    let rendered =
+                        self.render_html(&layout_path, &merged_blocks, &variables, None)?;
+                    self.include_chain.pop();
+                    return Ok(rendered);
+                }
+                None => {}
+            }
+        }
+
+        self.render_tokens(input_path, &source, &tokens, blocks, &variables)
+    }
+
+    /// Renders a run of tokens from `source`, the contents of `input_path`. Blocks with
+    /// an entry in `blocks` are replaced by it, otherwise their default content is kept.
+    fn render_tokens(
+        &mut self,
+        input_path: &Path,
+        source: &Source,
+        tokens: &[Token],
+        blocks: &HashMap<String, String>,
+        variables: &Variables,
+    ) -> std::io::Result<String> {
+        let mut output = String::new();
+
+        let mut index = 0;
+        while index < tokens.len() {
+            let token = tokens[index];
+            let (line, column) = source.position(token.offset);
+            index += 1;
+
+            match token.kind {
+                TokenKind::Text => {
+                    let text = self.substitute_variables(
+                        input_path,
+                        &source.text[token.start..token.end],
+                        |offset| source.position(token.start + offset),
+                        variables,
+                    );
+                    output.push_str(&text);
+                }
+                TokenKind::Block { name } => {
+                    let Some(end) = find_endblock(&tokens[index..]) else {
+                        self.report(
+                            Diagnostic::error(
+                                "unclosed-block",
+                                input_path,
+                                format!("Block {} is never closed", name),
+                            )
+                            .at(line, column),
+                        );
+                        continue;
+                    };
+                    match blocks.get(name) {
+                        Some(content) => output.push_str(content),
+                        None => output.push_str(&self.render_tokens(
+                            input_path,
+                            source,
+                            &tokens[index..index + end],
+                            blocks,
+                            variables,
+                        )?),
+                    }
+                    index += end + 1;
+                }
+                TokenKind::EndBlock => {
+                    self.report(
+                        Diagnostic::error("unexpected-endblock", input_path, "Unexpected endblock")
+                            .at(line, column),
+                    );
+                }
+                // Only meaningful before anything else in the file.
+                TokenKind::Extends { .. } => output.push_str(&source.text[token.start..token.end]),
+                TokenKind::Template { name, arguments } => {
+                    let Some(template_path) =
+                        self.resolve(input_path, name, Reference::Template, Some((line, column)))
+                    else {
+                        output.push_str(&source.text[token.start..token.end]);
+                        continue;
+                    };
+                    let arguments = match parse_arguments(arguments) {
+                        Ok(arguments) => arguments,
+                        Err(e) => {
+                            self.report(
+                                Diagnostic::error(
+                                    "invalid-arguments",
+                                    input_path,
+                                    format!("Invalid arguments for template {}: {}", name, e),
+                                )
+                                .at(line, column),
+                            );
+                            continue;
+                        }
+                    };
+                    let arguments: Variables = arguments
+                        .into_iter()
+                        .map(|(name, value)| {
+                            let value = self.substitute_variables(
+                                input_path,
+                                &value,
+                                |_| (line, column),
+                                variables,
+                            );
+                            (name, value)
+                        })
+                        .collect();
+
+                    if is_in_include_chain(self.config, &self.include_chain, &template_path)? {
+                        self.report(
+                            Diagnostic::error(
+                                "include-cycle",
+                                input_path,
+                                format!(
+                                    "Include cycle detected: {}",
+                                    format_include_chain(&self.include_chain, &template_path)
+                                ),
+                            )
+                            .at(line, column),
+                        );
+                        continue;
+                    }
+
+                    self.include_chain.push(template_path.clone());
+                    let template_content = self.render_html(
+                        &template_path,
+                        &HashMap::new(),
+                        variables,
+                        Some(Include {
+                            caller: input_path,
+                            line,
+                            column,
+                            arguments: &arguments,
+                        }),
+                    )?;
+                    self.include_chain.pop();
+                    // The template's last line break would otherwise add one to the
+                    // line the tag is on.
+                    let template_content = template_content
+                        .strip_suffix('\n')
+                        .map(|content| content.strip_suffix('\r').unwrap_or(content))
+                        .unwrap_or(&template_content);
+                    if self.config.indent_includes
+                        && raw_depth(&source.text[..token.offset], 0) == 0
+                    {
+                        output.push_str(&indent(
+                            template_content,
+                            line_indentation(source.text, token.offset),
+                        ));
+                    } else {
+                        output.push_str(template_content);
+                    }
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    /// Renders the top-level blocks of a page that extends a layout. Anything outside a
+    /// block is discarded, since the layout decides what surrounds them.
+    fn collect_blocks(
+        &mut self,
+        input_path: &Path,
+        source: &Source,
+        tokens: &[Token],
+        blocks: &HashMap<String, String>,
+        variables: &Variables,
+    ) -> std::io::Result<HashMap<String, String>> {
+        let mut collected = HashMap::new();
+
+        let mut index = 0;
+        while index < tokens.len() {
+            let token = tokens[index];
+            index += 1;
+
+            let TokenKind::Block { name } = token.kind else {
+                continue;
+            };
+            let Some(end) = find_endblock(&tokens[index..]) else {
+                let (line, column) = source.position(token.offset);
+                self.report(
+                    Diagnostic::error(
+                        "unclosed-block",
+                        input_path,
+                        format!("Block {} is never closed", name),
+                    )
+                    .at(line, column),
+                );
+                break;
+            };
+            let content = self.render_tokens(
+                input_path,
+                source,
+                &tokens[index..index + end],
+                blocks,
+                variables,
+            )?;
+            collected.insert(name.to_string(), content);
+            index += end + 1;
+        }
+        Ok(collected)
+    }
+
+    /// Replaces every `{{ name }}` placeholder in `text`. Undefined variables are
+    /// reported at the line and column `position` gives for the byte offset of their
+    /// placeholder in `text`, and left in place. A backslash in front of a placeholder
+    /// keeps it as it is, minus the backslash, for pages that show or use the syntax
+    /// themselves, e.g. client-side templates.
+    fn substitute_variables(
+        &mut self,
+        input_path: &Path,
+        text: &str,
+        position: impl Fn(usize) -> (usize, usize),
+        variables: &Variables,
+    ) -> String {
+        let mut undefined = Vec::new();
+        let substituted = VARIABLE_REGEX.replace_all(text, |captures: &regex::Captures| {
+            if let Some(backslash) = captures.get(1) {
+                return captures[0][backslash.len()..].to_string();
+            }
+            let name = captures.get(2).unwrap().as_str();
+            match variables.get(name) {
+                Some(value) => value.clone(),
+                None => {
+                    let offset = captures.get(0).unwrap().start();
+                    undefined.push((name.to_string(), position(offset)));
+                    captures.get(0).unwrap().as_str().to_string()
+                }
+            }
+        });
+        let substituted = substituted.into_owned();
+
+        for (name, (line, column)) in undefined {
+            self.report(
+                Diagnostic::error(
+                    "undefined-variable",
+                    input_path,
+                    format!("Variable {} is not defined", name),
+                )
+                .at(line, column),
+            );
+        }
+        substituted
+    }
+}
+
+/// Looks `name` up in the template search paths, then next to `input_path`. Names
+/// starting with `/` are relative to the source directory or a search path instead.
+pub fn resolve_template(config: &SiteConfig, input_path: &Path, name: &str) -> Option<PathBuf> {
+    template_candidates(config, input_path, name)
+        .into_iter()
+        .find(|path| config.source_fs.exists(path))
+}
+
+/// Every place `resolve_template` looks, in order: the template paths, then the theme,
+/// then the directory of the including file. The theme's files are looked up through
+/// the source directory, so a site file at the same path still replaces the theme's.
+fn template_candidates(config: &SiteConfig, input_path: &Path, name: &str) -> Vec<PathBuf> {
+    match name.strip_prefix('/') {
+        Some(name) => std::iter::once(&config.source_dir)
+            .chain(&config.template_paths)
+            .map(|root| root.join(name))
+            .collect(),
+        None => {
+            let theme = config
+                .theme
+                .iter()
+                .filter(|theme| config.source_fs.exists(&theme.root.join(name)))
+                .map(|_| config.source_dir.join(name));
+            config
+                .template_paths
+                .iter()
+                .map(|root| root.join(name))
+                .chain(theme)
+                .chain(std::iter::once(input_path.parent().unwrap().join(name)))
+                .collect()
+        }
+    }
+}
+
+/// Whether `path` is inside the source directory or one of the template search paths,
+/// once `..` and links are resolved.
+fn is_within_roots(config: &SiteConfig, path: &Path) -> bool {
+    let Ok(path) = config.source_fs.canonicalize(path) else {
+        return false;
+    };
+    std::iter::once(&config.source_dir)
+        .chain(&config.template_paths)
+        .filter_map(|root| config.source_fs.canonicalize(root).ok())
+        .chain(config.theme.iter().map(|theme| theme.root.clone()))
+        .any(|root| path.starts_with(root))
+}
+
+/// The spaces and tabs starting the line that byte `offset` of `source` is on.
+fn line_indentation(source: &str, offset: usize) -> &str {
+    let line_start = source[..offset].rfind('\n').map_or(0, |index| index + 1);
+    let line = &source[line_start..offset];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Prefixes every line of `content` but the first, which continues the tag's line, with
+/// `indentation`. Blank lines and lines inside `<pre>` or `<textarea>` are left alone,
+/// since whitespace there is part of the content.
+fn indent(content: &str, indentation: &str) -> String {
+    let mut indented = String::new();
+    let mut depth = 0;
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        if index > 0 && depth == 0 && !line.trim().is_empty() {
+            indented.push_str(indentation);
+        }
+        indented.push_str(line);
+        depth = raw_depth(line, depth);
+    }
+    indented
+}
+
+/// How many `<pre>` and `<textarea>` elements are open after `text`, given `depth` open
+/// before it.
+fn raw_depth(text: &str, depth: usize) -> usize {
+    RAW_TAG_REGEX
+        .captures_iter(text)
+        .fold(depth, |depth, captures| match captures[1].is_empty() {
+            true => depth + 1,
+            false => depth.saturating_sub(1),
+        })
+}
+
+/// Parses the `name="value"` pairs following a template name in an include tag.
+fn parse_arguments(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut arguments = Vec::new();
+    let mut rest = text;
+    while !rest.trim().is_empty() {
+        let Some(captures) = ARGUMENT_REGEX.captures(rest) else {
+            return Err(format!("expected name=\"value\", found {:?}", rest.trim()));
+        };
+        arguments.push((
+            captures.get(1).unwrap().as_str().to_string(),
+            captures.get(2).unwrap().as_str().to_string(),
+        ));
+        rest = &rest[captures.get(0).unwrap().end()..];
+    }
+    Ok(arguments)
+}
+
+/// Finds the index of the endblock tag closing a block whose body starts at `tokens[0]`,
+/// skipping over nested blocks.
+fn find_endblock(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Block { .. } => depth += 1,
+            TokenKind::EndBlock if depth == 0 => return Some(index),
+            TokenKind::EndBlock => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_in_include_chain(
+    config: &SiteConfig,
+    include_chain: &[PathBuf],
+    path: &Path,
+) -> std::io::Result<bool> {
+    let canonical_path = config.source_fs.canonicalize(path)?;
+    for included in include_chain {
+        if config.source_fs.canonicalize(included)? == canonical_path {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Formats an include chain as `a.html -> b.html -> a.html`, closing it with `repeated`.
+fn format_include_chain(include_chain: &[PathBuf], repeated: &Path) -> String {
+    include_chain
+        .iter()
+        .map(|path| path.as_path())
+        .chain(std::iter::once(repeated))
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::site::{Builder, Site};
+    use crate::vfs::MemoryFs;
+    use std::sync::Arc;
+
+    fn in_memory_site(files: &[(&str, &str)]) -> Site {
+        let sources = MemoryFs::new();
+        for (path, contents) in files {
+            sources.insert(path, *contents);
+        }
+        Builder::new()
+            .source_dir("src")
+            .output_dir("out")
+            .variable("user", "Ada")
+            .source_fs(sources)
+            .output_fs(Arc::new(MemoryFs::new()))
+            .site()
+            .unwrap()
+    }
+
+    fn codes(diagnostics: &[crate::Diagnostic]) -> Vec<&str> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect()
+    }
+
+    #[test]
+    fn escaped_placeholders_are_kept() {
+        let site = in_memory_site(&[(
+            "src/vue.html",
+            "<p>{{ user }}</p><script>const t = `\\{{ user }} \\{{name}}`</script>",
+        )]);
+        let page = site.render_page("src/vue.html").unwrap();
+        assert_eq!(
+            page.html,
+            "<p>Ada</p><script>const t = `{{ user }} {{name}}`</script>"
+        );
+        assert!(
+            page.diagnostics.is_empty(),
+            "{:?}",
+            codes(&page.diagnostics)
+        );
+    }
+
+    #[test]
+    fn markdown_bodies_expand_templates_and_variables() {
+        let site = in_memory_site(&[
+            ("src/_card.html", "<div class=\"card\">{{ user }}</div>\n"),
+            (
+                "src/post.md",
+                "---\ntitle: Hello\n---\n# {{ title }}\n\n<!-- template: _card.html -->\n\nKeep \\{{ this }}.\n",
+            ),
+        ]);
+        let page = site.render_page("src/post.md").unwrap();
+        assert!(
+            page.diagnostics.is_empty(),
+            "{:?}",
+            codes(&page.diagnostics)
+        );
+        assert_eq!(
+            page.html,
+            "<h1 id=\"hello\">Hello</h1>\n<div class=\"card\">Ada</div>\n<p>Keep {{ this }}.</p>\n"
+        );
+
+        let site = in_memory_site(&[(
+            "src/post.md",
+            "Hi {{ title }}\n<!-- template: missing.html -->\n",
+        )]);
+        let page = site.render_page("src/post.md").unwrap();
+        assert_eq!(
+            codes(&page.diagnostics),
+            ["undefined-variable", "missing-template"]
+        );
+        assert_eq!(
+            (page.diagnostics[1].line, page.diagnostics[1].column),
+            (Some(2), Some(1))
+        );
+    }
+
+    #[test]
+    fn arguments_a_template_does_not_declare_are_warnings() {
+        let site = in_memory_site(&[
+            (
+                "src/_card.html",
+                "+++\nparams = [\"title\"]\n+++\n<h2>{{ title }}</h2>",
+            ),
+            (
+                "src/index.html",
+                "<!-- template: _card.html title=\"A\" titel=\"B\" -->",
+            ),
+        ]);
+        let page = site.render_page("src/index.html").unwrap();
+        assert_eq!(page.html, "<h2>A</h2>");
+        assert_eq!(codes(&page.diagnostics), ["unused-argument"]);
+        assert_eq!(page.diagnostics[0].severity, crate::Severity::Warning);
+        assert_eq!(
+            (page.diagnostics[0].line, page.diagnostics[0].column),
+            (Some(1), Some(1))
+        );
+    }
+
+    #[test]
+    fn templates_come_from_template_paths_then_the_theme_then_next_to_the_page() {
+        let sources = MemoryFs::new();
+        sources.insert("theme/theme.toml", "");
+        sources.insert("theme/src/card.html", "theme");
+        sources.insert("theme/src/nav.html", "theme");
+        sources.insert("src/index.html", "<!-- template: card.html -->");
+        sources.insert(
+            "src/blog/post.html",
+            "<!-- template: card.html --> <!-- template: nav.html --> <!-- template: tag.html -->",
+        );
+        sources.insert("src/blog/card.html", "sibling");
+        sources.insert("src/blog/tag.html", "sibling");
+        sources.insert("src/nav.html", "site");
+        sources.insert("templates/tag.html", "templates");
+        let site = Builder::new()
+            .source_dir("src")
+            .output_dir("out")
+            .template_path("templates")
+            .theme("theme")
+            .source_fs(sources)
+            .output_fs(Arc::new(MemoryFs::new()))
+            .site()
+            .unwrap();
+
+        let page = site.render_page("src/blog/post.html").unwrap();
+        assert!(
+            page.diagnostics.is_empty(),
+            "{:?}",
+            codes(&page.diagnostics)
+        );
+        // The site's nav.html replaces the theme's, and the templates directory still
+        // comes before the page's own.
+        assert_eq!(page.html, "theme site templates");
+        assert_eq!(site.render_page("src/index.html").unwrap().html, "theme");
+    }
+
+    #[test]
+    fn undefined_variables_are_errors() {
+        let site = in_memory_site(&[("src/index.html", "<p>{{ title }}</p>")]);
+        let page = site.render_page("src/index.html").unwrap();
+        assert_eq!(page.html, "<p>{{ title }}</p>");
+        assert_eq!(codes(&page.diagnostics), ["undefined-variable"]);
+        assert_eq!(page.diagnostics[0].severity, crate::Severity::Error);
+        assert_eq!(
+            (page.diagnostics[0].line, page.diagnostics[0].column),
+            (Some(1), Some(4))
+        );
+    }
+}
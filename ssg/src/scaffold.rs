@@ -0,0 +1,68 @@
+use crate::config::CONFIG_FILE_NAME;
+use std::fs;
+use std::path::Path;
+
+const SITE_TOML: &str = r#"source_dir = "src"
+output_dir = "generated"
+base_url = ""
+
+[variables]
+site_name = "My Site"
+"#;
+
+const LAYOUT_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>{{ title }} | {{ site_name }}</title>
+</head>
+<body>
+    <!-- template: header.html -->
+    <main>
+<!-- block: content -->
+<!-- endblock -->
+    </main>
+</body>
+</html>
+"#;
+
+const HEADER_HTML: &str = r#"<header><a href="{{ base_url }}/">{{ site_name }}</a></header>
+"#;
+
+const INDEX_HTML: &str = r#"+++
+title = "Home"
++++
+<!-- extends: _layout.html -->
+<!-- block: content -->
+<h1>Welcome to {{ site_name }}</h1>
+<p>Read the <a href="hello.html">first post</a>.</p>
+<!-- endblock -->
+"#;
+
+const HELLO_MD: &str = r#"+++
+title = "Hello"
++++
+# Hello
+
+This page is written in Markdown and wrapped in `_layout.html`.
+"#;
+
+/// Creates a minimal site in `path`: a config, a layout with a header partial, an HTML
+/// page and a Markdown page. Refuses to write into a non-empty directory.
+pub fn new_site(path: &Path) -> std::io::Result<()> {
+    if path.exists() && fs::read_dir(path)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists and is not empty", path),
+        ));
+    }
+
+    let src = path.join("src");
+    fs::create_dir_all(&src)?;
+    fs::write(path.join(CONFIG_FILE_NAME), SITE_TOML)?;
+    fs::write(src.join("_layout.html"), LAYOUT_HTML)?;
+    fs::write(src.join("header.html"), HEADER_HTML)?;
+    fs::write(src.join("index.html"), INDEX_HTML)?;
+    fs::write(src.join("hello.md"), HELLO_MD)?;
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use crate::diagnostics::{self, Diagnostic, MessageFormat};
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Path the injected script subscribes to for build notifications.
+const LIVE_RELOAD_PATH: &str = "/__livereload";
+
+/// Reloads the page after a good build and shows an overlay with the errors after a bad
+/// one. Injected before `</body>` of every HTML page served.
+const LIVE_RELOAD_SCRIPT: &str = r#"<script>
+(function () {
+    var source = new EventSource("/__livereload");
+    source.onmessage = function (event) {
+        var build = JSON.parse(event.data);
+        if (build.errors.length === 0) {
+            location.reload();
+            return;
+        }
+        var overlay = document.getElementById("__livereload-overlay");
+        if (!overlay) {
+            overlay = document.createElement("pre");
+            overlay.id = "__livereload-overlay";
+            overlay.style.cssText = "position:fixed;inset:0;z-index:2147483647;margin:0;" +
+                "padding:2em;overflow:auto;background:rgba(20,0,0,0.92);color:#ff8080;" +
+                "font:14px/1.5 monospace;white-space:pre-wrap;";
+            document.body.appendChild(overlay);
+        }
+        overlay.textContent = "Build failed:\n\n" + build.errors.join("\n");
+    };
+})();
+</script>
+"#;
+
+/// Outcome of the most recent build, shared between the watcher and open connections.
+#[derive(Default)]
+pub struct LiveReload {
+    state: Mutex<BuildState>,
+    changed: Condvar,
+}
+
+#[derive(Default, Clone)]
+struct BuildState {
+    /// Bumped after every build, so connections can tell they missed one.
+    generation: u64,
+    /// Empty when the last build succeeded.
+    errors: Vec<String>,
+}
+
+impl LiveReload {
+    /// Records a finished build and wakes every connected browser.
+    pub fn notify(&self, diagnostics: &[Diagnostic], warnings_as_errors: bool) {
+        let errors = if diagnostics::has_errors(diagnostics, warnings_as_errors) {
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostics::render(diagnostic, MessageFormat::Short))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.notify_errors(errors);
+    }
+
+    /// Records a build that failed outright, e.g. because the output couldn't be written.
+    pub fn notify_errors(&self, errors: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.errors = errors;
+        self.changed.notify_all();
+    }
+}
+
+/// Serves the files under `root` on localhost until the process is stopped, pushing
+/// build results from `live_reload` to every open page.
+pub fn serve(root: &Path, port: u16, live_reload: Arc<LiveReload>) -> std::io::Result<()> {
+    let server = tiny_http::Server::http(("127.0.0.1", port)).map_err(std::io::Error::other)?;
+    println!("Serving {:?} at http://127.0.0.1:{}/", root, port);
+
+    for request in server.incoming_requests() {
+        let root = root.to_path_buf();
+        let live_reload = Arc::clone(&live_reload);
+        // Event streams stay open for as long as the page does, so every request gets
+        // its own thread rather than holding up the others.
+        std::thread::spawn(move || {
+            if let Err(e) = handle_request(request, &root, &live_reload) {
+                eprintln!("Error responding to request: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_request(
+    request: tiny_http::Request,
+    root: &Path,
+    live_reload: &LiveReload,
+) -> std::io::Result<()> {
+    let url_path = request
+        .url()
+        .split(['?', '#'])
+        .next()
+        .unwrap_or("")
+        .to_string();
+
+    if url_path == LIVE_RELOAD_PATH {
+        return stream_events(request, live_reload);
+    }
+
+    let Some(path) = resolve_request_path(root, &url_path) else {
+        return request.respond(not_found(root));
+    };
+    if path.is_dir() {
+        // Redirect so relative links inside the index page resolve against the directory.
+        let location = format!("{}/", url_path);
+        let response = tiny_http::Response::empty(301).with_header(header("Location", &location));
+        return request.respond(response);
+    }
+
+    match fs::read(&path) {
+        Ok(body) => request.respond(file_response(&path, body, 200)),
+        Err(_) => request.respond(not_found(root)),
+    }
+}
+
+/// Maps a request path to a file under `root`, rejecting paths that climb out of it.
+/// Directories resolve to their `index.html`, and `/about` falls back to `about.html`.
+/// A directory requested without its trailing slash is returned as is.
+fn resolve_request_path(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(url_path)?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    if path.is_dir() {
+        if !decoded.ends_with('/') && path != root {
+            return Some(path);
+        }
+        path.push("index.html");
+    } else if !path.exists() && path.extension().is_none() {
+        path.set_extension("html");
+    }
+    Some(path).filter(|path| path.is_file())
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = text.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Responds with `404.html` from the output directory if the site has one.
+fn not_found(root: &Path) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let custom_page = root.join("404.html");
+    match fs::read(&custom_page) {
+        Ok(body) => file_response(&custom_page, body, 404),
+        Err(_) => tiny_http::Response::from_string("404 Not Found")
+            .with_status_code(404)
+            .with_header(header("Content-Type", "text/plain; charset=utf-8")),
+    }
+}
+
+fn file_response(
+    path: &Path,
+    mut body: Vec<u8>,
+    status: u16,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let content_type = content_type(path);
+    if content_type.starts_with("text/html") {
+        body = inject_live_reload(body);
+    }
+    tiny_http::Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", content_type))
+        .with_header(header("Cache-Control", "no-store"))
+}
+
+/// Inserts the live reload script before the last `</body>`, or appends it.
+fn inject_live_reload(body: Vec<u8>) -> Vec<u8> {
+    let lowercase = body.to_ascii_lowercase();
+    let position = lowercase
+        .windows(b"</body>".len())
+        .rposition(|window| window == b"</body>")
+        .unwrap_or(body.len());
+
+    let mut injected = Vec::with_capacity(body.len() + LIVE_RELOAD_SCRIPT.len());
+    injected.extend_from_slice(&body[..position]);
+    injected.extend_from_slice(LIVE_RELOAD_SCRIPT.as_bytes());
+    injected.extend_from_slice(&body[position..]);
+    injected
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "xml" => "application/xml",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn header(name: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Holds a server-sent events stream open, sending the build state whenever it changes
+/// (and straight away if the last build failed). Returns once the browser goes away.
+fn stream_events(request: tiny_http::Request, live_reload: &LiveReload) -> std::io::Result<()> {
+    let mut writer = request.into_writer();
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/event-stream\r\n\
+          Cache-Control: no-store\r\n\
+          Connection: keep-alive\r\n\r\n",
+    )?;
+    writer.flush()?;
+
+    let mut state = live_reload.state.lock().unwrap();
+    let mut seen = state.generation;
+    if !state.errors.is_empty() {
+        let event = build_event(&state);
+        drop(state);
+        writer.write_all(event.as_bytes())?;
+        writer.flush()?;
+        state = live_reload.state.lock().unwrap();
+    }
+
+    loop {
+        let (next, timeout) = live_reload
+            .changed
+            .wait_timeout_while(state, Duration::from_secs(15), |state| {
+                state.generation == seen
+            })
+            .unwrap();
+        let message = if timeout.timed_out() {
+            // Comments keep proxies from closing the stream and reveal closed pages.
+            ": keep-alive\n\n".to_string()
+        } else {
+            seen = next.generation;
+            build_event(&next)
+        };
+        drop(next);
+
+        writer.write_all(message.as_bytes())?;
+        writer.flush()?;
+        state = live_reload.state.lock().unwrap();
+    }
+}
+
+fn build_event(state: &BuildState) -> String {
+    format!(
+        "data: {}\n\n",
+        serde_json::json!({ "errors": state.errors })
+    )
+}
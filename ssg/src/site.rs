@@ -0,0 +1,645 @@
+use crate::build::{self, Build};
+use crate::config::{LineEndings, SiteConfig, Verbosity};
+use crate::diagnostics::{self, Diagnostic, MessageFormat};
+use crate::manifest;
+use crate::vfs::{self, FileSystem};
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A site and the state of its last build, for programs that embed the generator.
+///
+/// ```no_run
+/// let mut site = generate::Builder::new()
+///     .source_dir("content")
+///     .output_dir("public")
+///     .variable("site_name", "Example")
+///     .site()?;
+/// let report = site.build()?;
+/// for output in &report.outputs {
+///     println!("{:?} -> {:?}", output.source, output.path);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Site {
+    config: SiteConfig,
+    /// `None` until the first build.
+    build: Option<Build>,
+}
+
+/// Settings for a [`Site`], starting from the defaults used without a config file, but
+/// quiet and without a build cache.
+pub struct Builder {
+    config: SiteConfig,
+}
+
+/// What a build produced.
+pub struct BuildReport {
+    /// Every page and asset of the site, in source order.
+    pub outputs: Vec<Output>,
+    /// Errors and warnings, in source order.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Whether the diagnostics fail the build. A failed build leaves the previous output
+    /// in place.
+    pub failed: bool,
+}
+
+/// A source and the file it is published as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub source: PathBuf,
+    pub path: PathBuf,
+}
+
+/// A page rendered in memory.
+pub struct RenderedPage {
+    pub html: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Site {
+    /// Takes `config` as it is. The first build calls [`SiteConfig::finish`] on it if
+    /// that wasn't done yet.
+    pub fn new(config: SiteConfig) -> Site {
+        Site {
+            config,
+            build: None,
+        }
+    }
+
+    /// Loads `config_path`, or `site.toml` from the working directory if it exists.
+    pub fn load(config_path: Option<&Path>) -> std::io::Result<Site> {
+        let mut config = SiteConfig::load(config_path)?;
+        config.finish()?;
+        Ok(Site::new(config))
+    }
+
+    pub fn config(&self) -> &SiteConfig {
+        &self.config
+    }
+
+    /// Replaces the settings. The next [`Site::update`] builds everything.
+    pub fn set_config(&mut self, config: SiteConfig) {
+        self.config = config;
+        self.build = None;
+    }
+
+    /// Builds every page and asset into the output directory, skipping what the cache
+    /// says is unchanged.
+    pub fn build(&mut self) -> std::io::Result<BuildReport> {
+        self.config.finish()?;
+        if !self.config.dry_run {
+            self.config
+                .output_fs
+                .create_dir_all(&self.config.output_dir)?;
+        }
+        self.build = Some(Build::full(&self.config)?);
+        Ok(self.report())
+    }
+
+    /// Brings the output up to date after the files at `changed` were edited, rendering
+    /// only what depends on them. Builds everything if the site wasn't built yet.
+    pub fn update(&mut self, changed: &[PathBuf]) -> std::io::Result<BuildReport> {
+        match &mut self.build {
+            Some(build) => build.update(&self.config, changed)?,
+            None => return self.build(),
+        }
+        Ok(self.report())
+    }
+
+    /// Builds everything again, e.g. after the source directory was replaced.
+    pub fn rebuild(&mut self, reason: &str) -> std::io::Result<BuildReport> {
+        match &mut self.build {
+            Some(build) => build.rebuild_all(&self.config, reason)?,
+            None => return self.build(),
+        }
+        Ok(self.report())
+    }
+
+    /// Every file the last build read or looked for.
+    pub fn inputs(&self) -> Vec<PathBuf> {
+        match &self.build {
+            Some(build) => build.inputs().into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Renders the HTML or Markdown page at `path` to a string, without touching the
+    /// output directory or the cache.
+    pub fn render_page(&self, path: impl AsRef<Path>) -> std::io::Result<RenderedPage> {
+        let mut diagnostics = Vec::new();
+        let html = build::render_page(&self.config, path.as_ref(), &mut diagnostics)?;
+        Ok(RenderedPage { html, diagnostics })
+    }
+
+    /// Writes the output of the last build to a zip archive, leaving out the files that
+    /// only matter to later builds. Pair with a [`MemoryFs`](crate::MemoryFs) as
+    /// `output_fs` to build an archive without touching the disk.
+    pub fn write_zip(&self, writer: impl Write + Seek) -> std::io::Result<()> {
+        vfs::write_zip(
+            self.config.output_fs.as_ref(),
+            &self.config.output_dir,
+            |path| path == Path::new(manifest::MANIFEST_FILE_NAME),
+            writer,
+        )
+    }
+
+    /// Deletes the output directory and the build cache, refusing when the directory
+    /// wasn't created by a build or would take the sources or the working directory
+    /// with it.
+    pub fn clean(&mut self) -> std::io::Result<()> {
+        self.build = None;
+        let config = &self.config;
+        let fs = config.output_fs.as_ref();
+        if let Some(cache_file) = &config.cache_file {
+            match fs.remove_file(cache_file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if !fs.exists(&config.output_dir) {
+            return Ok(());
+        }
+        if !manifest::exists(fs, &config.output_dir) && !fs.read_dir(&config.output_dir)?.is_empty()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "Refusing to delete {:?}: it has no {} so it wasn't created by a build",
+                    config.output_dir,
+                    manifest::MANIFEST_FILE_NAME
+                ),
+            ));
+        }
+        let output_dir = fs.canonicalize(&config.output_dir)?;
+        let current_dir = fs.canonicalize(&std::env::current_dir()?).ok();
+        if current_dir.is_some_and(|current_dir| current_dir.starts_with(&output_dir))
+            || config.overlaps_output_dir(&config.source_dir)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "Refusing to delete {:?}: it contains the sources or the working directory",
+                    config.output_dir
+                ),
+            ));
+        }
+        fs.remove_dir_all(&output_dir)?;
+        if config.verbosity >= Verbosity::Normal {
+            println!("Removed {:?}", config.output_dir);
+        }
+        Ok(())
+    }
+
+    fn report(&self) -> BuildReport {
+        let build = self.build.as_ref().unwrap();
+        let diagnostics = build.diagnostics();
+        BuildReport {
+            outputs: build.outputs(&self.config),
+            failed: diagnostics::has_errors(&diagnostics, self.config.warnings_as_errors),
+            diagnostics,
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            config: SiteConfig {
+                verbosity: Verbosity::Quiet,
+                ..SiteConfig::default()
+            },
+        }
+    }
+
+    /// Starts from settings loaded elsewhere, e.g. with [`SiteConfig::load`].
+    pub fn from_config(config: SiteConfig) -> Builder {
+        Builder { config }
+    }
+
+    pub fn source_dir(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.config.source_dir = path.into();
+        self
+    }
+
+    pub fn output_dir(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.config.output_dir = path.into();
+        self
+    }
+
+    /// Adds a directory to search for templates and layouts, after the ones added
+    /// before it.
+    pub fn template_path(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.config.template_paths.push(path.into());
+        self
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Builder {
+        self.config.set_base_url(base_url.into());
+        self
+    }
+
+    /// Sets a site-wide variable, which page front matter can override.
+    pub fn variable(mut self, name: impl Into<String>, value: impl Into<String>) -> Builder {
+        self.config.variables.insert(name.into(), value.into());
+        self
+    }
+
+    /// Leaves files matching `pattern`, relative to the source directory, out of the
+    /// build.
+    pub fn ignore(mut self, pattern: &str) -> std::io::Result<Builder> {
+        let pattern = glob::Pattern::new(pattern).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid ignore pattern {:?}: {}", pattern, e),
+            )
+        })?;
+        self.config.ignore.push(pattern);
+        Ok(self)
+    }
+
+    /// Remembers what each build read and wrote in `path`, so later builds skip
+    /// unchanged pages.
+    pub fn cache_file(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.config.cache_file = Some(path.into());
+        self
+    }
+
+    /// Builds the site on top of the theme directory or archive at `path`, read through
+    /// `source_fs`.
+    pub fn theme(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.config.theme_path = Some(path.into());
+        self
+    }
+
+    /// Reads the sources and templates from `fs` instead of the disk.
+    pub fn source_fs(mut self, fs: impl FileSystem + 'static) -> Builder {
+        self.config.source_fs = Arc::new(fs);
+        self
+    }
+
+    /// Writes the output, cache and staging directories to `fs` instead of the disk.
+    pub fn output_fs(mut self, fs: impl FileSystem + 'static) -> Builder {
+        self.config.output_fs = Arc::new(fs);
+        self
+    }
+
+    pub fn line_endings(mut self, line_endings: LineEndings) -> Builder {
+        self.config.line_endings = line_endings;
+        self
+    }
+
+    /// Indents included templates like the line their tag is on, except inside `<pre>`
+    /// and `<textarea>`.
+    pub fn indent_includes(mut self, indent_includes: bool) -> Builder {
+        self.config.indent_includes = indent_includes;
+        self
+    }
+
+    /// Renders everything and reports errors, but writes nothing.
+    pub fn dry_run(mut self, dry_run: bool) -> Builder {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    pub fn warnings_as_errors(mut self, warnings_as_errors: bool) -> Builder {
+        self.config.warnings_as_errors = warnings_as_errors;
+        self
+    }
+
+    /// How much progress to print. Quiet by default.
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Builder {
+        self.config.verbosity = verbosity;
+        self
+    }
+
+    /// The finished settings. Fails if the theme can't be read or the site leaves a
+    /// variable it requires unset.
+    pub fn config(mut self) -> std::io::Result<SiteConfig> {
+        self.config.finish()?;
+        Ok(self.config)
+    }
+
+    pub fn site(self) -> std::io::Result<Site> {
+        Ok(Site::new(self.config()?))
+    }
+
+    /// Builds the site once.
+    pub fn build(self) -> std::io::Result<BuildReport> {
+        self.site()?.build()
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl BuildReport {
+    /// Prints the diagnostics and the outcome of the build the way the command line
+    /// does, and writes the SARIF report if `config` asks for one.
+    pub fn print(&self, config: &SiteConfig) -> std::io::Result<()> {
+        for diagnostic in &self.diagnostics {
+            println!("{}", diagnostics::render(diagnostic, config.message_format));
+        }
+
+        if let Some(sarif_path) = &config.sarif_path {
+            diagnostics::write_sarif(&self.diagnostics, sarif_path)?;
+        }
+
+        if config.message_format == MessageFormat::Human && config.verbosity >= Verbosity::Normal {
+            if self.failed {
+                println!("\x1b[31mGeneration failed due to errors.\x1b[0m");
+                println!("\x1b[33mFix it and run again :^)\x1b[0m");
+            } else if self.diagnostics.is_empty() {
+                println!("\x1b[32mStatic site generation complete.\x1b[0m");
+            } else {
+                println!("\x1b[33mStatic site generation completed with warnings.\x1b[0m");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Severity;
+    use crate::vfs::{ArchiveFs, MemoryFs};
+
+    fn in_memory_site(sources: &Arc<MemoryFs>, output: &Arc<MemoryFs>) -> Site {
+        Builder::new()
+            .source_dir("src")
+            .output_dir("out")
+            .variable("site_name", "Test")
+            .source_fs(sources.clone())
+            .output_fs(output.clone())
+            .site()
+            .unwrap()
+    }
+
+    fn read(fs: &Arc<MemoryFs>, path: &str) -> String {
+        fs.read_to_string(Path::new(path)).unwrap()
+    }
+
+    #[test]
+    fn builds_from_memory_into_memory() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert(
+            "src/_layout.html",
+            "<main><!-- block: content --><!-- endblock --></main>\n",
+        );
+        sources.insert("src/_nav.html", "<nav>{{ site_name }}</nav>");
+        sources.insert(
+            "src/index.html",
+            "<!-- extends: _layout.html -->\n<!-- block: content --><!-- template: _nav.html -->Home<!-- endblock -->\n",
+        );
+        sources.insert("src/blog/post.md", "# Post\n");
+        sources.insert("src/style.css", "p {}");
+        let output = Arc::new(MemoryFs::new());
+
+        let report = in_memory_site(&sources, &output).build().unwrap();
+        assert!(!report.failed, "{:?}", report.diagnostics);
+        let published: Vec<&Path> = report
+            .outputs
+            .iter()
+            .map(|output| output.path.as_path())
+            .collect();
+        assert_eq!(
+            published,
+            [
+                Path::new("out/blog/post.html"),
+                Path::new("out/index.html"),
+                Path::new("out/style.css")
+            ]
+        );
+        assert_eq!(
+            read(&output, "out/index.html"),
+            "<main><nav>Test</nav>Home</main>\n"
+        );
+        assert_eq!(
+            read(&output, "out/blog/post.html"),
+            "<main><h1 id=\"post\">Post</h1>\n</main>\n"
+        );
+        assert_eq!(read(&output, "out/style.css"), "p {}");
+        assert!(!output.exists(Path::new("out/_nav.html")));
+        assert!(!output.exists(Path::new(".out.staging")));
+    }
+
+    #[test]
+    fn updates_rebuild_pages_left_stale_by_a_failed_build() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert("src/_p.html", "P1");
+        sources.insert("src/a.html", "a <!-- template: _p.html -->");
+        sources.insert("src/b.html", "b <!-- template: _p.html -->");
+        let output = Arc::new(MemoryFs::new());
+        let mut site = in_memory_site(&sources, &output);
+        assert!(!site.build().unwrap().failed);
+
+        sources.insert("src/_p.html", "P2");
+        sources.insert("src/b.html", "b <!-- template: _p.html --> {{ undefined }}");
+        let report = site
+            .update(&[PathBuf::from("src/_p.html"), PathBuf::from("src/b.html")])
+            .unwrap();
+        assert!(report.failed);
+        assert_eq!(read(&output, "out/a.html"), "a P1");
+        assert_eq!(read(&output, "out/b.html"), "b P1");
+
+        sources.insert("src/b.html", "b <!-- template: _p.html -->");
+        let report = site.update(&[PathBuf::from("src/b.html")]).unwrap();
+        assert!(!report.failed, "{:?}", report.diagnostics);
+        assert_eq!(read(&output, "out/a.html"), "a P2");
+        assert_eq!(read(&output, "out/b.html"), "b P2");
+    }
+
+    #[test]
+    fn editing_a_template_outside_the_sources_only_renders_its_pages() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert("templates/h.html", "H1");
+        sources.insert("src/a.html", "a <!-- template: h.html -->");
+        sources.insert("src/b.html", "b");
+        let output = Arc::new(MemoryFs::new());
+        let mut site = Builder::new()
+            .source_dir("src")
+            .output_dir("out")
+            .template_path("templates")
+            .source_fs(sources.clone())
+            .output_fs(output.clone())
+            .site()
+            .unwrap();
+        assert!(!site.build().unwrap().failed);
+
+        // Only a full build would find this, since nobody reports it.
+        sources.insert("src/unnoticed.html", "new");
+        sources.insert("templates/h.html", "H2");
+        let report = site.update(&[PathBuf::from("templates/h.html")]).unwrap();
+        assert!(!report.failed, "{:?}", report.diagnostics);
+        assert_eq!(read(&output, "out/a.html"), "a H2");
+        assert!(!output.exists(Path::new("out/unnoticed.html")));
+    }
+
+    #[test]
+    fn render_page_leaves_the_output_alone() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert("src/index.html", "<p>{{ site_name }} {{ missing }}</p>");
+        let output = Arc::new(MemoryFs::new());
+        let site = in_memory_site(&sources, &output);
+
+        let page = site.render_page("src/index.html").unwrap();
+        assert_eq!(page.html, "<p>Test {{ missing }}</p>");
+        let codes: Vec<&str> = page
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect();
+        assert_eq!(codes, ["undefined-variable"]);
+        assert!(!output.exists(Path::new("out")));
+    }
+
+    #[test]
+    fn warnings_fail_the_build_only_when_asked() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert(
+            "src/_card.html",
+            "+++\nparams = [\"title\"]\n+++\n{{ title }}",
+        );
+        sources.insert(
+            "src/index.html",
+            "<!-- template: _card.html title=\"A\" size=\"2\" -->",
+        );
+        let output = Arc::new(MemoryFs::new());
+        let report = in_memory_site(&sources, &output).build().unwrap();
+        assert!(!report.failed);
+        assert_eq!(report.diagnostics[0].severity, Severity::Warning);
+        assert_eq!(read(&output, "out/index.html"), "A");
+
+        let output = Arc::new(MemoryFs::new());
+        let report = Builder::new()
+            .source_dir("src")
+            .output_dir("out")
+            .warnings_as_errors(true)
+            .source_fs(sources.clone())
+            .output_fs(output.clone())
+            .build()
+            .unwrap();
+        assert!(report.failed);
+        assert!(!output.exists(Path::new("out/index.html")));
+    }
+
+    #[test]
+    fn reports_partials_no_page_reaches() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert("src/index.html", "<p>Home</p>");
+        sources.insert("src/_unused.html", "<!-- template: card.html -->");
+        sources.insert("src/card.html", "<div></div>");
+        let output = Arc::new(MemoryFs::new());
+        let report = in_memory_site(&sources, &output).build().unwrap();
+        assert!(!report.failed);
+        let codes: Vec<&str> = report.diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, ["unreachable-partial"]);
+        assert_eq!(report.diagnostics[0].file, Path::new("src/card.html"));
+
+        sources.insert("src/a.html", "<!-- template: b.html -->");
+        sources.insert("src/b.html", "<!-- template: a.html -->");
+        let report = in_memory_site(&sources, &output).build().unwrap();
+        assert!(report.failed);
+        let files: Vec<(&Path, &str)> = report
+            .diagnostics
+            .iter()
+            .map(|d| (d.file.as_path(), d.code))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                (Path::new("src/a.html"), "include-cycle"),
+                (Path::new("src/b.html"), "include-cycle"),
+                (Path::new("src/card.html"), "unreachable-partial"),
+            ]
+        );
+    }
+
+    #[test]
+    fn cleans_only_what_a_build_created() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert("src/index.html", "<p>Home</p>");
+        let output = Arc::new(MemoryFs::new());
+        let mut site = in_memory_site(&sources, &output);
+        assert!(!site.build().unwrap().failed);
+        site.clean().unwrap();
+        assert!(!output.exists(Path::new("out")));
+        // Nothing to clean is fine, and so is an empty directory.
+        site.clean().unwrap();
+        output.create_dir_all(Path::new("out")).unwrap();
+        site.clean().unwrap();
+        assert!(!output.exists(Path::new("out")));
+
+        output.insert("out/mine.txt", "");
+        let error = site.clean().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(output.exists(Path::new("out/mine.txt")));
+    }
+
+    #[test]
+    fn refuses_to_clean_the_sources_or_the_working_directory() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.insert("site/src/index.html", "<p>Home</p>");
+        fs.insert(Path::new("site").join(manifest::MANIFEST_FILE_NAME), "[]");
+        let mut site = Site::new(SiteConfig {
+            source_dir: PathBuf::from("site/src"),
+            output_dir: PathBuf::from("site"),
+            verbosity: Verbosity::Quiet,
+            source_fs: fs.clone(),
+            output_fs: fs.clone(),
+            ..SiteConfig::default()
+        });
+        let error = site.clean().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(fs.exists(Path::new("site/src/index.html")));
+
+        // The in-memory filesystem has no working directory of its own, so give it one
+        // where the process has it and clean the directory above.
+        let current_dir = std::env::current_dir().unwrap();
+        let top: PathBuf = current_dir.components().take(2).collect();
+        let fs = Arc::new(MemoryFs::new());
+        fs.insert(current_dir.join("page.html"), "");
+        fs.insert(top.join(manifest::MANIFEST_FILE_NAME), "[]");
+        let mut site = Site::new(SiteConfig {
+            source_dir: PathBuf::from("src"),
+            output_dir: top.clone(),
+            verbosity: Verbosity::Quiet,
+            source_fs: fs.clone(),
+            output_fs: fs.clone(),
+            ..SiteConfig::default()
+        });
+        let error = site.clean().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(fs.exists(&current_dir.join("page.html")));
+    }
+
+    #[test]
+    fn zips_the_output_without_the_manifest() {
+        let sources = Arc::new(MemoryFs::new());
+        sources.insert("src/index.html", "<p>Home</p>");
+        sources.insert("src/docs/guide.md", "Guide\n");
+        let output = Arc::new(MemoryFs::new());
+        let mut site = in_memory_site(&sources, &output);
+        assert!(!site.build().unwrap().failed);
+        assert!(output.exists(&Path::new("out").join(manifest::MANIFEST_FILE_NAME)));
+
+        let mut zip = std::io::Cursor::new(Vec::new());
+        site.write_zip(&mut zip).unwrap();
+        let archive = ArchiveFs::from_zip(zip).unwrap();
+        assert_eq!(
+            archive.read_to_string(Path::new("index.html")).unwrap(),
+            "<p>Home</p>"
+        );
+        assert_eq!(
+            archive
+                .read_to_string(Path::new("docs/guide.html"))
+                .unwrap(),
+            "<p>Guide</p>\n"
+        );
+        assert!(!archive.exists(Path::new(manifest::MANIFEST_FILE_NAME)));
+    }
+}
@@ -0,0 +1,411 @@
+use crate::config::{SiteConfig, Verbosity};
+use crate::diagnostics::Diagnostic;
+use crate::front_matter;
+use crate::render;
+use crate::template::{self, TokenKind};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Layout that wraps every Markdown page in its directory and below, unless the page
+/// picks one with a `layout` front matter key.
+pub const DEFAULT_LAYOUT: &str = "_layout.html";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceKind {
+    /// An HTML page, rendered to the same relative path.
+    Page,
+    /// A Markdown page, rendered to the same relative path with an `.html` extension.
+    Markdown,
+    /// A template or layout, only ever rendered as part of a page.
+    Partial,
+    /// Anything else, copied as is.
+    Asset,
+}
+
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// Path relative to the source directory.
+    pub relative: PathBuf,
+    pub canonical: PathBuf,
+    pub kind: SourceKind,
+}
+
+impl SourceFile {
+    /// Where this file ends up under `output_dir`, or `None` for partials.
+    pub fn output_path(&self, output_dir: &Path) -> Option<PathBuf> {
+        match self.kind {
+            SourceKind::Page | SourceKind::Asset => Some(output_dir.join(&self.relative)),
+            SourceKind::Markdown => Some(output_dir.join(&self.relative).with_extension("html")),
+            SourceKind::Partial => None,
+        }
+    }
+}
+
+/// Every file in the source directory, classified, plus what each one includes.
+pub struct Sources {
+    /// Sorted by relative path, so builds run in the same order on every platform.
+    pub files: Vec<SourceFile>,
+    /// Templates and layouts each page or partial refers to, by canonical path.
+    pub references: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Problems with the set of files itself, such as two sources published to the same
+    /// path, by the canonical path of the source they are reported on.
+    pub diagnostics: HashMap<PathBuf, Vec<Diagnostic>>,
+    /// Canonical paths of the sources left out because another is published to the same
+    /// path.
+    collisions: HashSet<PathBuf>,
+    /// Position of each file in `files`, by canonical path.
+    index: HashMap<PathBuf, usize>,
+    /// Canonical paths of the directories holding a file, at any depth.
+    dirs: HashSet<PathBuf>,
+}
+
+/// Walks the source directory and classifies every file before anything is rendered.
+/// A file is a partial when it or one of its directories starts with `_`, or when any
+/// other source includes, extends or uses it as a layout. This keeps partials out of
+/// the output no matter which order the directory is read in.
+pub fn scan(config: &SiteConfig) -> std::io::Result<Sources> {
+    let mut paths = Vec::new();
+    collect_files(config, &config.source_dir, &mut paths)?;
+    paths.sort();
+
+    let mut sources = Sources {
+        files: Vec::new(),
+        references: HashMap::new(),
+        diagnostics: HashMap::new(),
+        collisions: HashSet::new(),
+        index: HashMap::new(),
+        dirs: HashSet::new(),
+    };
+    for path in paths {
+        let canonical = config.source_fs.canonicalize(&path)?;
+        if is_template(&path) {
+            let references = find_references(config, &path)?;
+            sources.references.insert(canonical.clone(), references);
+        }
+        let relative = path
+            .strip_prefix(&config.source_dir)
+            .unwrap_or(&path)
+            .to_path_buf();
+        for dir in canonical.ancestors().skip(1) {
+            if !sources.dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+        sources.index.insert(canonical.clone(), sources.files.len());
+        sources.files.push(SourceFile {
+            path,
+            relative,
+            canonical,
+            kind: SourceKind::Asset,
+        });
+    }
+    sources.classify();
+    Ok(sources)
+}
+
+impl Sources {
+    pub fn get(&self, canonical: &Path) -> Option<&SourceFile> {
+        self.index.get(canonical).map(|&index| &self.files[index])
+    }
+
+    /// Whether any source lives in the directory at `canonical`, or below it.
+    pub fn contains_dir(&self, canonical: &Path) -> bool {
+        self.dirs.contains(canonical)
+    }
+
+    /// Sources that include, extend or use `path` as their layout directly.
+    pub fn dependents(&self, config: &SiteConfig, path: &Path) -> Vec<&Path> {
+        let Ok(path) = config.source_fs.canonicalize(path) else {
+            return Vec::new();
+        };
+        self.files
+            .iter()
+            .filter(|file| {
+                self.references
+                    .get(&file.canonical)
+                    .is_some_and(|references| references.contains(&path))
+            })
+            .map(|file| file.path.as_path())
+            .collect()
+    }
+
+    /// Scans the source at `canonical` again after it was edited. Returns whether that
+    /// turned any page into a partial or the other way round.
+    pub fn refresh(&mut self, config: &SiteConfig, canonical: &Path) -> std::io::Result<bool> {
+        let Some(file) = self.get(canonical) else {
+            return Ok(false);
+        };
+        if !is_template(&file.path) {
+            return Ok(false);
+        }
+        let references = find_references(config, &file.path)?;
+        self.references.insert(canonical.to_path_buf(), references);
+
+        let kinds: Vec<SourceKind> = self.files.iter().map(|file| file.kind).collect();
+        self.classify();
+        Ok(self.files.iter().map(|file| file.kind).ne(kinds))
+    }
+
+    fn classify(&mut self) {
+        let referenced: HashSet<&PathBuf> = self.references.values().flatten().collect();
+        let kinds: Vec<SourceKind> = self
+            .files
+            .iter()
+            .map(|file| {
+                if is_underscored(&file.relative) || referenced.contains(&file.canonical) {
+                    SourceKind::Partial
+                } else {
+                    match file.path.extension().and_then(|s| s.to_str()) {
+                        Some("html") => SourceKind::Page,
+                        Some("md") => SourceKind::Markdown,
+                        _ => SourceKind::Asset,
+                    }
+                }
+            })
+            .collect();
+        for (file, kind) in self.files.iter_mut().zip(kinds) {
+            file.kind = kind;
+        }
+        self.diagnostics.clear();
+        self.find_collisions();
+        self.find_unreachable();
+    }
+
+    /// Reports every source published to the same path as one before it, e.g. `a.md`
+    /// next to `a.html`. Only the first is built, so they don't race for the file.
+    fn find_collisions(&mut self) {
+        self.collisions.clear();
+        let mut published: HashMap<PathBuf, &SourceFile> = HashMap::new();
+        for file in &self.files {
+            let Some(output_path) = file.output_path(Path::new("")) else {
+                continue;
+            };
+            match published.get(&output_path) {
+                Some(first) => {
+                    self.collisions.insert(file.canonical.clone());
+                    self.diagnostics.insert(
+                        file.canonical.clone(),
+                        vec![Diagnostic::error(
+                            "output-collision",
+                            &file.path,
+                            format!(
+                                "Would be published as {:?}, which {:?} already is",
+                                output_path, first.path
+                            ),
+                        )],
+                    );
+                }
+                None => {
+                    published.insert(output_path, file);
+                }
+            }
+        }
+    }
+
+    /// Reports the files that are partials only because other partials include them,
+    /// when no page reaches them: they would silently vanish from the output. Files that
+    /// include each other that way are an error, since neither can ever be published.
+    fn find_unreachable(&mut self) {
+        let mut reachable: HashSet<&Path> = HashSet::new();
+        let mut pending: Vec<&Path> = self
+            .files
+            .iter()
+            .filter(|file| file.kind != SourceKind::Partial)
+            .map(|file| file.canonical.as_path())
+            .collect();
+        while let Some(path) = pending.pop() {
+            if reachable.insert(path) {
+                pending.extend(
+                    self.references
+                        .get(path)
+                        .into_iter()
+                        .flatten()
+                        .map(PathBuf::as_path),
+                );
+            }
+        }
+
+        let mut diagnostics = Vec::new();
+        for file in &self.files {
+            if file.kind != SourceKind::Partial
+                || reachable.contains(file.canonical.as_path())
+                || is_underscored(&file.relative)
+            {
+                continue;
+            }
+            let includers: Vec<&Path> = self
+                .files
+                .iter()
+                .filter(|includer| {
+                    self.references
+                        .get(&includer.canonical)
+                        .is_some_and(|references| references.contains(&file.canonical))
+                })
+                .map(|includer| includer.path.as_path())
+                .collect();
+            let Some(first) = includers.first() else {
+                continue;
+            };
+            let diagnostic = if self.includes(&file.canonical, &file.canonical) {
+                Diagnostic::error(
+                    "include-cycle",
+                    &file.path,
+                    format!(
+                        "Never published: it is a partial because {:?} includes it, and it \
+                         includes that file in turn, so no page reaches either",
+                        first
+                    ),
+                )
+            } else {
+                Diagnostic::warning(
+                    "unreachable-partial",
+                    &file.path,
+                    format!(
+                        "Never published: it is a partial because {:?} includes it, but no \
+                         page includes that file",
+                        first
+                    ),
+                )
+            };
+            diagnostics.push((file.canonical.clone(), diagnostic));
+        }
+        for (canonical, diagnostic) in diagnostics {
+            self.diagnostics
+                .entry(canonical)
+                .or_default()
+                .push(diagnostic);
+        }
+    }
+
+    /// Whether `from` includes `to`, directly or through other sources.
+    fn includes(&self, from: &Path, to: &Path) -> bool {
+        let mut seen: HashSet<&Path> = HashSet::new();
+        let mut pending = vec![from];
+        while let Some(path) = pending.pop() {
+            for reference in self.references.get(path).into_iter().flatten() {
+                if reference == to {
+                    return true;
+                }
+                if seen.insert(reference) {
+                    pending.push(reference);
+                }
+            }
+        }
+        false
+    }
+
+    /// Whether `canonical` is left out of the build because another source is
+    /// published to the same path.
+    pub fn collides(&self, canonical: &Path) -> bool {
+        self.collisions.contains(canonical)
+    }
+}
+
+/// Whether `relative` or one of its directories starts with `_`.
+fn is_underscored(relative: &Path) -> bool {
+    relative
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with('_'))
+}
+
+/// Whether `path` can include other files.
+fn is_template(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|s| s.to_str()),
+        Some("html") | Some("md")
+    )
+}
+
+/// Nearest `_layout.html` in the directory of `path` or above it, up to the source
+/// directory.
+pub fn default_layout(config: &SiteConfig, path: &Path) -> Option<PathBuf> {
+    layout_candidates(config, path)
+        .into_iter()
+        .find(|layout_path| config.source_fs.is_file(layout_path))
+}
+
+/// Every place `default_layout` looks, nearest first.
+pub fn layout_candidates(config: &SiteConfig, path: &Path) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        candidates.push(current.join(DEFAULT_LAYOUT));
+        if current == config.source_dir {
+            break;
+        }
+        dir = current.parent();
+    }
+    candidates
+}
+
+fn collect_files(config: &SiteConfig, dir: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for path in config.source_fs.read_dir(dir)? {
+        if config.is_ignored(&path) || is_template_path(config, &path) {
+            if config.verbosity >= Verbosity::Verbose {
+                println!("Skipped: {:?}", path);
+            }
+            continue;
+        }
+        if config.source_fs.is_dir(&path) {
+            collect_files(config, &path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Canonical paths of the templates and layouts `path` refers to. References that don't
+/// resolve are left for the renderer to report.
+fn find_references(config: &SiteConfig, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let source = config.source_fs.read_to_string(path)?;
+    let source = source.trim_start_matches('\u{feff}');
+    let (tokens, _) = template::tokenize(source, 0);
+    let mut names: Vec<String> = tokens
+        .iter()
+        .filter_map(|token| match token.kind {
+            TokenKind::Template { name, .. } | TokenKind::Extends { name } => {
+                Some(name.to_string())
+            }
+            _ => None,
+        })
+        .collect();
+    let mut uses_default_layout = false;
+    if path.extension().and_then(|s| s.to_str()) == Some("md") {
+        let lines: Vec<&str> = source.lines().collect();
+        let front_matter = front_matter::parse(&lines)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        match front_matter.variables.get("layout") {
+            Some(layout_name) => names.push(layout_name.clone()),
+            None => uses_default_layout = true,
+        }
+    }
+
+    let mut references = Vec::new();
+    for name in &names {
+        if let Some(resolved) = render::resolve_template(config, path, name) {
+            references.push(config.source_fs.canonicalize(&resolved)?);
+        }
+    }
+    if uses_default_layout {
+        if let Some(layout_path) = default_layout(config, path) {
+            references.push(config.source_fs.canonicalize(&layout_path)?);
+        }
+    }
+    Ok(references)
+}
+
+/// Whether `path` is one of the template search paths, which hold partials and
+/// layouts that are never published on their own.
+fn is_template_path(config: &SiteConfig, path: &Path) -> bool {
+    let Ok(path) = config.source_fs.canonicalize(path) else {
+        return false;
+    };
+    config
+        .template_paths
+        .iter()
+        .filter_map(|template_path| config.source_fs.canonicalize(template_path).ok())
+        .any(|template_path| template_path == path)
+}
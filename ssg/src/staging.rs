@@ -0,0 +1,331 @@
+use crate::vfs::FileSystem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A copy of the output directory that a build writes into, swapped into place only
+/// once the build succeeded. Servers and deploy scripts reading the output directory
+/// never see a half-written or failed build.
+///
+/// After a swap the staging directory holds the previous output. It is kept, and only
+/// the files that differ are linked again before the next build, so a small change
+/// doesn't pay for linking the whole site. It is removed when the `Staging` is dropped.
+pub struct Staging {
+    fs: Arc<dyn FileSystem>,
+    output_dir: PathBuf,
+    staging_dir: PathBuf,
+    /// Files, relative to both directories, that may differ between the staging
+    /// directory and the output. `None` when the staging directory has to be set up from
+    /// scratch.
+    changed: Option<Vec<PathBuf>>,
+}
+
+impl Staging {
+    /// Stages into `.<name>.staging` next to `output_dir`. Returns `None` when
+    /// `output_dir` has no name to derive the staging directory from, in which case the
+    /// build writes in place.
+    pub fn new(fs: Arc<dyn FileSystem>, output_dir: &Path) -> Option<Staging> {
+        Some(Staging {
+            fs,
+            output_dir: output_dir.to_path_buf(),
+            staging_dir: sibling(output_dir, "staging")?,
+            changed: None,
+        })
+    }
+
+    /// Makes the staging directory a copy of the current output, made of hard links so
+    /// unchanged files keep their contents and modification times without being copied.
+    pub fn prepare(&mut self) -> std::io::Result<()> {
+        let fs = self.fs.as_ref();
+        match self.changed.take() {
+            Some(changed) => {
+                for path in changed {
+                    sync_file(
+                        fs,
+                        &self.output_dir.join(&path),
+                        &self.staging_dir.join(&path),
+                    )?;
+                }
+            }
+            None => {
+                if fs.exists(&self.staging_dir) {
+                    // Left behind by a build that was interrupted.
+                    fs.remove_dir_all(&self.staging_dir)?;
+                }
+                fs.create_dir_all(&self.staging_dir)?;
+                if fs.is_dir(&self.output_dir) {
+                    link_tree(fs, &self.output_dir, &self.staging_dir)?;
+                }
+            }
+        }
+        self.changed = Some(Vec::new());
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.staging_dir
+    }
+
+    /// Swaps the staging directory with the output directory. Where the filesystem can
+    /// exchange two directories in one step the output never goes missing; elsewhere
+    /// there is a moment between two renames when it does. `written` lists the files
+    /// the build wrote, or `None` if it may have written anything.
+    pub fn commit(&mut self, written: Option<Vec<PathBuf>>) -> std::io::Result<()> {
+        let fs = self.fs.as_ref();
+        self.changed = None;
+        if !fs.exists(&self.output_dir) {
+            return fs.rename(&self.staging_dir, &self.output_dir);
+        }
+        match fs.exchange(&self.staging_dir, &self.output_dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                let previous_dir = sibling(&self.output_dir, "old").unwrap();
+                if fs.exists(&previous_dir) {
+                    fs.remove_dir_all(&previous_dir)?;
+                }
+                fs.rename(&self.output_dir, &previous_dir)?;
+                fs.rename(&self.staging_dir, &self.output_dir)?;
+                fs.rename(&previous_dir, &self.staging_dir)?;
+            }
+            result => result?,
+        }
+        self.keep(written)
+    }
+
+    /// Throws the staged build away, leaving the output directory as it was. `written`
+    /// is as for [`Staging::commit`].
+    pub fn discard(&mut self, written: Option<Vec<PathBuf>>) -> std::io::Result<()> {
+        self.keep(written)
+    }
+
+    /// Keeps the staging directory for the next build if only `changed` differ from the
+    /// output. Otherwise linking everything again costs as much as starting over.
+    fn keep(&mut self, changed: Option<Vec<PathBuf>>) -> std::io::Result<()> {
+        if changed.is_none() && self.fs.exists(&self.staging_dir) {
+            self.fs.remove_dir_all(&self.staging_dir)?;
+        }
+        self.changed = changed;
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if self.changed.is_some() {
+            let _ = self.fs.remove_dir_all(&self.staging_dir);
+        }
+    }
+}
+
+/// Writers must replace staged files rather than write into them, or they would change
+/// the linked file in the live output too.
+pub fn write_file(fs: &dyn FileSystem, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    match fs.remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs.write(path, contents)
+}
+
+/// The directories next to `output_dir` that builds stage into and move the previous
+/// output aside to.
+pub fn work_dirs(output_dir: &Path) -> Vec<PathBuf> {
+    ["staging", "old"]
+        .into_iter()
+        .filter_map(|suffix| sibling(output_dir, suffix))
+        .collect()
+}
+
+/// `.<name>.<suffix>` in the same directory as `output_dir`, so renames between the two
+/// stay on one filesystem.
+pub fn sibling(output_dir: &Path, suffix: &str) -> Option<PathBuf> {
+    let name = output_dir.file_name()?.to_string_lossy();
+    Some(output_dir.with_file_name(format!(".{}.{}", name, suffix)))
+}
+
+/// Makes `to` a link to the file `from`, or removes it if `from` doesn't exist.
+fn sync_file(fs: &dyn FileSystem, from: &Path, to: &Path) -> std::io::Result<()> {
+    match fs.remove_file(to) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if !fs.is_file(from) {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    fs.hard_link(from, to)
+}
+
+/// Recreates the directories under `from` in `to`, hard linking the files, or copying
+/// them where links aren't supported.
+fn link_tree(fs: &dyn FileSystem, from: &Path, to: &Path) -> std::io::Result<()> {
+    for path in fs.read_dir(from)? {
+        let target = to.join(path.file_name().unwrap());
+        if fs.is_dir(&path) {
+            fs.create_dir_all(&target)?;
+            link_tree(fs, &path, &target)?;
+        } else {
+            fs.hard_link(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    /// A [`MemoryFs`] that leaves `exchange` to the trait's default, like a filesystem
+    /// that can't swap two directories atomically.
+    struct NoExchange(MemoryFs);
+
+    impl FileSystem for NoExchange {
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            self.0.read(path)
+        }
+
+        fn is_file(&self, path: &Path) -> bool {
+            self.0.is_file(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            self.0.is_dir(path)
+        }
+
+        fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+            self.0.read_dir(path)
+        }
+
+        fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.0.canonicalize(path)
+        }
+
+        fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+            self.0.write(path, contents)
+        }
+
+        fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+            self.0.create_dir_all(path)
+        }
+
+        fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+            self.0.remove_file(path)
+        }
+
+        fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+            self.0.remove_dir(path)
+        }
+
+        fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+            self.0.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            self.0.rename(from, to)
+        }
+
+        fn hard_link(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            self.0.hard_link(from, to)
+        }
+    }
+
+    fn read(fs: &dyn FileSystem, path: &str) -> String {
+        fs.read_to_string(Path::new(path)).unwrap()
+    }
+
+    fn with_output(fs: &dyn FileSystem) {
+        fs.create_dir_all(Path::new("out/blog")).unwrap();
+        fs.write(Path::new("out/index.html"), b"old").unwrap();
+        fs.write(Path::new("out/blog/post.html"), b"post").unwrap();
+    }
+
+    /// Stages a new `index.html`, checks the live output doesn't see it, and commits.
+    fn build_index(fs: &Arc<dyn FileSystem>, staging: &mut Staging, contents: &str) {
+        staging.prepare().unwrap();
+        assert_eq!(read(fs.as_ref(), ".out.staging/blog/post.html"), "post");
+        write_file(
+            fs.as_ref(),
+            Path::new(".out.staging/index.html"),
+            contents.as_bytes(),
+        )
+        .unwrap();
+        assert_ne!(read(fs.as_ref(), "out/index.html"), contents);
+        staging
+            .commit(Some(vec![PathBuf::from("index.html")]))
+            .unwrap();
+        assert_eq!(read(fs.as_ref(), "out/index.html"), contents);
+        assert_eq!(read(fs.as_ref(), "out/blog/post.html"), "post");
+    }
+
+    #[test]
+    fn commits_by_exchanging_and_keeps_the_previous_output() {
+        let fs: Arc<dyn FileSystem> = Arc::new(MemoryFs::new());
+        with_output(fs.as_ref());
+        let mut staging = Staging::new(fs.clone(), Path::new("out")).unwrap();
+        build_index(&fs, &mut staging, "new");
+        // The previous output is kept as the next staging directory...
+        assert_eq!(read(fs.as_ref(), ".out.staging/index.html"), "old");
+        // ...and only the file that changed is linked again.
+        build_index(&fs, &mut staging, "newer");
+        assert_eq!(read(fs.as_ref(), ".out.staging/index.html"), "new");
+
+        drop(staging);
+        assert!(!fs.exists(Path::new(".out.staging")));
+    }
+
+    #[test]
+    fn falls_back_to_renames_without_exchange() {
+        let fs: Arc<dyn FileSystem> = Arc::new(NoExchange(MemoryFs::new()));
+        with_output(fs.as_ref());
+        let mut staging = Staging::new(fs.clone(), Path::new("out")).unwrap();
+        build_index(&fs, &mut staging, "new");
+        assert_eq!(read(fs.as_ref(), ".out.staging/index.html"), "old");
+        assert!(!fs.exists(Path::new(".out.old")));
+
+        // One left behind by an interrupted swap doesn't get in the way.
+        fs.create_dir_all(Path::new(".out.old")).unwrap();
+        build_index(&fs, &mut staging, "newer");
+        assert!(!fs.exists(Path::new(".out.old")));
+    }
+
+    #[test]
+    fn first_commit_renames_into_place() {
+        let fs: Arc<dyn FileSystem> = Arc::new(MemoryFs::new());
+        let mut staging = Staging::new(fs.clone(), Path::new("out")).unwrap();
+        staging.prepare().unwrap();
+        write_file(fs.as_ref(), Path::new(".out.staging/index.html"), b"new").unwrap();
+        staging.commit(None).unwrap();
+        assert_eq!(read(fs.as_ref(), "out/index.html"), "new");
+        assert!(!fs.exists(Path::new(".out.staging")));
+    }
+
+    #[test]
+    fn discard_leaves_the_output_alone() {
+        let fs: Arc<dyn FileSystem> = Arc::new(MemoryFs::new());
+        with_output(fs.as_ref());
+        let mut staging = Staging::new(fs.clone(), Path::new("out")).unwrap();
+        staging.prepare().unwrap();
+        write_file(fs.as_ref(), Path::new(".out.staging/index.html"), b"bad").unwrap();
+        fs.remove_file(Path::new(".out.staging/blog/post.html"))
+            .unwrap();
+        staging
+            .discard(Some(vec![
+                PathBuf::from("index.html"),
+                PathBuf::from("blog/post.html"),
+            ]))
+            .unwrap();
+        assert_eq!(read(fs.as_ref(), "out/index.html"), "old");
+        assert_eq!(read(fs.as_ref(), ".out.staging/index.html"), "bad");
+
+        // The files the failed build touched are put back before the next one.
+        staging.prepare().unwrap();
+        assert_eq!(read(fs.as_ref(), ".out.staging/index.html"), "old");
+        assert_eq!(read(fs.as_ref(), ".out.staging/blog/post.html"), "post");
+
+        // Not knowing what was written means starting over.
+        staging.discard(None).unwrap();
+        assert!(!fs.exists(Path::new(".out.staging")));
+        assert_eq!(read(fs.as_ref(), "out/index.html"), "old");
+    }
+}
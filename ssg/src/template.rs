@@ -0,0 +1,349 @@
+/// A piece of a template. Text tokens and the tags between them cover the source
+/// without gaps, so writing out every token verbatim gives back the original.
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'s> {
+    pub kind: TokenKind<'s>,
+    /// Byte range in the source. A block, endblock or extends tag alone on its line
+    /// also covers that line's indentation and line break, so removing it leaves no
+    /// blank line behind.
+    pub start: usize,
+    pub end: usize,
+    /// Byte offset of the `<!--` opening a tag, or of the start of a text token.
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind<'s> {
+    /// Anything that isn't a tag, including ordinary comments.
+    Text,
+    /// `<!-- template: name.html arg="value" -->` includes another file.
+    Template {
+        name: &'s str,
+        arguments: &'s str,
+    },
+    /// `<!-- extends: layout.html -->` names the layout a page fills in.
+    Extends {
+        name: &'s str,
+    },
+    /// `<!-- block: name -->` opens a block, closed by `<!-- endblock -->`.
+    Block {
+        name: &'s str,
+    },
+    EndBlock,
+}
+
+/// A tag that couldn't be read. What it covers is kept as text.
+pub struct Error {
+    pub code: &'static str,
+    pub message: String,
+    /// Byte offset of the `<!--` it starts with.
+    pub offset: usize,
+}
+
+/// Splits `source` from byte `start` on into text and tags. Tags may sit anywhere in a
+/// line, several to a line, and span lines.
+pub fn tokenize(source: &str, start: usize) -> (Vec<Token<'_>>, Vec<Error>) {
+    let mut tags = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut position = start;
+    while let Some(found) = source[position..].find("<!--") {
+        let offset = position + found;
+        let body_start = offset + "<!--".len();
+        let Some(length) = source[body_start..].find("-->") else {
+            errors.push(Error {
+                code: "unterminated-comment",
+                message: "Comment is never closed with -->".to_string(),
+                offset,
+            });
+            break;
+        };
+        let end = body_start + length + "-->".len();
+        position = end;
+        match parse_tag(&source[body_start..body_start + length]) {
+            Ok(Some(kind)) => tags.push(Token {
+                kind,
+                start: offset,
+                end,
+                offset,
+            }),
+            Ok(None) => {}
+            Err(message) => errors.push(Error {
+                code: "empty-tag-name",
+                message,
+                offset,
+            }),
+        }
+    }
+
+    for tag in &mut tags {
+        if !matches!(tag.kind, TokenKind::Template { .. }) {
+            extend_standalone(source, start, tag);
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut position = start;
+    for tag in tags {
+        if tag.start > position {
+            tokens.push(text(position, tag.start));
+        }
+        position = tag.end;
+        tokens.push(tag);
+    }
+    if source.len() > position {
+        tokens.push(text(position, source.len()));
+    }
+    (tokens, errors)
+}
+
+/// Byte offset of zero-based `line` in `source`, or its length if it has fewer lines.
+pub fn line_offset(source: &str, line: usize) -> usize {
+    source
+        .split_inclusive('\n')
+        .take(line)
+        .map(|line| line.len())
+        .sum()
+}
+
+/// A template's text along with where each of its lines starts, so byte offsets can be
+/// turned into line and column numbers without scanning from the top every time.
+pub struct Source<'s> {
+    pub text: &'s str,
+    line_starts: Vec<usize>,
+}
+
+impl<'s> Source<'s> {
+    pub fn new(text: &'s str) -> Source<'s> {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        Source { text, line_starts }
+    }
+
+    /// One-based line and character column of byte `offset`.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self
+            .line_starts
+            .partition_point(|&line_start| line_start <= offset);
+        let line_start = self.line_starts[line - 1];
+        (line, self.text[line_start..offset].chars().count() + 1)
+    }
+}
+
+fn text(start: usize, end: usize) -> Token<'static> {
+    Token {
+        kind: TokenKind::Text,
+        start,
+        end,
+        offset: start,
+    }
+}
+
+/// Reads the inside of a comment. `None` for comments that aren't tags.
+fn parse_tag(body: &str) -> Result<Option<TokenKind<'_>>, String> {
+    let body = body.trim();
+    if body == "endblock" {
+        return Ok(Some(TokenKind::EndBlock));
+    }
+    let Some((keyword, rest)) = body.split_once(':') else {
+        return Ok(None);
+    };
+    let rest = rest.trim_start();
+    let kind = match keyword {
+        "template" => {
+            let name_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            TokenKind::Template {
+                name: &rest[..name_end],
+                arguments: &rest[name_end..],
+            }
+        }
+        "extends" => TokenKind::Extends { name: rest },
+        "block" => TokenKind::Block { name: rest },
+        _ => return Ok(None),
+    };
+    if rest.is_empty() {
+        return Err(format!("The {} tag has no name", keyword));
+    }
+    Ok(Some(kind))
+}
+
+/// Widens `tag` to its whole line if nothing but whitespace shares the line with it.
+fn extend_standalone(source: &str, start: usize, tag: &mut Token) {
+    let line_start = source[start..tag.start]
+        .rfind('\n')
+        .map_or(start, |index| start + index + 1);
+    let line_end = source[tag.end..]
+        .find('\n')
+        .map_or(source.len(), |index| tag.end + index + 1);
+    let is_blank = |text: &str| text.chars().all(char::is_whitespace);
+    if is_blank(&source[line_start..tag.start]) && is_blank(&source[tag.end..line_end]) {
+        tag.start = line_start;
+        tag.end = line_end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds<'s>(tokens: &[Token<'s>]) -> Vec<TokenKind<'s>> {
+        tokens.iter().map(|token| token.kind).collect()
+    }
+
+    fn concatenate(source: &str, tokens: &[Token]) -> String {
+        tokens
+            .iter()
+            .map(|token| &source[token.start..token.end])
+            .collect()
+    }
+
+    #[test]
+    fn tag_mid_line() {
+        let source = "<p>Hello <!-- template: name.html --> there</p>\n";
+        let (tokens, errors) = tokenize(source, 0);
+        assert!(errors.is_empty());
+        assert_eq!(
+            kinds(&tokens),
+            [
+                TokenKind::Text,
+                TokenKind::Template {
+                    name: "name.html",
+                    arguments: ""
+                },
+                TokenKind::Text,
+            ]
+        );
+        assert_eq!(&source[tokens[0].start..tokens[0].end], "<p>Hello ");
+        assert_eq!(&source[tokens[2].start..tokens[2].end], " there</p>\n");
+    }
+
+    #[test]
+    fn several_tags_per_line() {
+        let source = "<!-- template: a.html --><!-- template: b.html x=\"1\" --> <!-- c -->";
+        let (tokens, errors) = tokenize(source, 0);
+        assert!(errors.is_empty());
+        assert_eq!(
+            kinds(&tokens),
+            [
+                TokenKind::Template {
+                    name: "a.html",
+                    arguments: ""
+                },
+                TokenKind::Template {
+                    name: "b.html",
+                    arguments: " x=\"1\""
+                },
+                TokenKind::Text,
+            ]
+        );
+    }
+
+    #[test]
+    fn tag_across_lines() {
+        let source = "before\n<!--\n  template: card.html\n  title=\"A\"\n-->after";
+        let (tokens, errors) = tokenize(source, 0);
+        assert!(errors.is_empty());
+        assert_eq!(
+            kinds(&tokens),
+            [
+                TokenKind::Text,
+                TokenKind::Template {
+                    name: "card.html",
+                    arguments: "\n  title=\"A\""
+                },
+                TokenKind::Text,
+            ]
+        );
+        assert_eq!(Source::new(source).position(tokens[1].offset), (2, 1));
+    }
+
+    #[test]
+    fn standalone_block_tags_cover_their_line() {
+        let source = "a\n  <!-- block: main -->\nbody\n<!-- endblock -->\nz";
+        let (tokens, _) = tokenize(source, 0);
+        assert_eq!(
+            kinds(&tokens),
+            [
+                TokenKind::Text,
+                TokenKind::Block { name: "main" },
+                TokenKind::Text,
+                TokenKind::EndBlock,
+                TokenKind::Text,
+            ]
+        );
+        assert_eq!(
+            &source[tokens[1].start..tokens[1].end],
+            "  <!-- block: main -->\n"
+        );
+        assert_eq!(&source[tokens[2].start..tokens[2].end], "body\n");
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        let source = "ok\n  <!-- template: a.html\n";
+        let (tokens, errors) = tokenize(source, 0);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "unterminated-comment");
+        assert_eq!(Source::new(source).position(errors[0].offset), (2, 3));
+        assert_eq!(kinds(&tokens), [TokenKind::Text]);
+        assert_eq!(concatenate(source, &tokens), source);
+    }
+
+    #[test]
+    fn empty_names() {
+        let source = "<!-- template: --> <!-- block:--> <!-- extends:   -->";
+        let (tokens, errors) = tokenize(source, 0);
+        let codes: Vec<&str> = errors.iter().map(|error| error.code).collect();
+        assert_eq!(codes, ["empty-tag-name"; 3]);
+        assert_eq!(kinds(&tokens), [TokenKind::Text]);
+    }
+
+    #[test]
+    fn ordinary_comments_are_text() {
+        let source = "<!-- just a note --><!-- endblocks -->";
+        let (tokens, errors) = tokenize(source, 0);
+        assert!(errors.is_empty());
+        assert_eq!(kinds(&tokens), [TokenKind::Text]);
+    }
+
+    #[test]
+    fn tokens_concatenate_to_the_source() {
+        let sources = [
+            "",
+            "plain text",
+            "---\ntitle: x\n---\n<!-- extends: _layout.html -->\n<!-- block: a -->\n  hi <!-- template: b.html k=\"v\" -->\r\n<!-- endblock -->\n",
+            "<pre>\n  <!-- template: code.html -->\n</pre><!-- unclosed",
+            "héllo <!-- block: ü -->wörld<!-- endblock -->",
+        ];
+        for source in sources {
+            let (tokens, _) = tokenize(source, 0);
+            assert_eq!(concatenate(source, &tokens), source);
+        }
+    }
+
+    #[test]
+    fn tokenizing_starts_at_the_given_offset() {
+        let source = "<!-- template: skipped.html -->\n<!-- template: kept.html -->";
+        let start = line_offset(source, 1);
+        let (tokens, _) = tokenize(source, start);
+        assert_eq!(tokens[0].start, start);
+        assert_eq!(
+            kinds(&tokens),
+            [TokenKind::Template {
+                name: "kept.html",
+                arguments: ""
+            }]
+        );
+    }
+
+    #[test]
+    fn positions_count_characters() {
+        let source = Source::new("ab\ncdé <!--\n\nx");
+        assert_eq!(source.position(0), (1, 1));
+        assert_eq!(source.position(2), (1, 3));
+        assert_eq!(source.position(3), (2, 1));
+        assert_eq!(source.position(source.text.find("<!--").unwrap()), (2, 5));
+        assert_eq!(source.position(source.text.len()), (4, 2));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::SiteConfig;
+use crate::front_matter::{self, Variables};
+use crate::vfs::{ArchiveFs, FileSystem, OverlayFs};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Name of the file describing a theme, at the top of its directory or archive.
+pub const THEME_FILE_NAME: &str = "theme.toml";
+
+/// Layouts, partials and assets a site is built on top of. The theme's `src` directory
+/// is merged beneath the site's: a site file with the same relative path replaces the
+/// theme's.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: String,
+    /// The theme directory or archive, as configured.
+    pub path: PathBuf,
+    /// The file whose changes change the theme's settings: its `theme.toml`, or the
+    /// archive.
+    pub config_path: PathBuf,
+    /// What the theme's sources canonicalize to. Templates may come from here too.
+    pub root: PathBuf,
+}
+
+/// On-disk layout of `theme.toml`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ThemeFile {
+    name: Option<String>,
+    /// Variables the site has to set to something other than an empty string.
+    required: Vec<String>,
+    /// Defaults for site-wide variables, overridden by the site's own.
+    variables: toml::Table,
+}
+
+/// Merges the theme at `path`, a directory or a tar, tar.gz or zip archive, beneath the
+/// sources of `config` and fills in its default variables. Fails when the site leaves
+/// a variable the theme requires unset.
+pub fn apply(config: &mut SiteConfig, path: &Path) -> std::io::Result<()> {
+    let source_fs = config.source_fs.clone();
+    // `root` is where the overlay pretends the theme's sources live. Files in an archive
+    // have no path of their own, so theirs are made up under the archive's.
+    let (lower, theme_dir, root, config_path): (Arc<dyn FileSystem>, _, _, _) =
+        if source_fs.is_dir(path) {
+            let root = source_fs.canonicalize(&path.join("src"))?;
+            (
+                source_fs.clone(),
+                path.to_path_buf(),
+                root,
+                path.join(THEME_FILE_NAME),
+            )
+        } else {
+            let bytes = source_fs.read(path).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("Error reading theme {:?}: {}", path, e))
+            })?;
+            let archive = ArchiveFs::from_bytes(bytes).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("Error reading theme {:?}: {}", path, e))
+            })?;
+            let theme_dir = archive_root(&archive);
+            let root = source_fs.canonicalize(path)?.join(&theme_dir).join("src");
+            (Arc::new(archive), theme_dir, root, path.to_path_buf())
+        };
+
+    let theme_file_path = theme_dir.join(THEME_FILE_NAME);
+    let contents = lower.read_to_string(&theme_file_path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "Error reading {:?} of theme {:?}: {}",
+                THEME_FILE_NAME, path, e
+            ),
+        )
+    })?;
+    let file: ThemeFile = toml::from_str(&contents).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Error parsing {:?} of theme {:?}: {}",
+                THEME_FILE_NAME, path, e
+            ),
+        )
+    })?;
+    let name = file.name.unwrap_or_else(|| {
+        path.file_stem()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let mut defaults = Variables::new();
+    for (key, value) in &file.variables {
+        front_matter::flatten_toml(key, value, &mut defaults);
+    }
+    for (key, value) in defaults {
+        match config.variables.get(&key) {
+            Some(current) if !current.is_empty() => {}
+            _ => {
+                config.variables.insert(key, value);
+            }
+        }
+    }
+    if let Some(base_url) = config.variables.get("base_url") {
+        config.base_url = base_url.clone();
+    }
+    let missing: Vec<&str> = file
+        .required
+        .iter()
+        .filter(|key| {
+            config
+                .variables
+                .get(*key)
+                .is_none_or(|value| value.is_empty())
+        })
+        .map(|key| key.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Theme {:?} requires the site to set {}",
+                name,
+                missing.join(", ")
+            ),
+        ));
+    }
+
+    config.source_fs = Arc::new(OverlayFs::new(
+        source_fs,
+        config.source_dir.clone(),
+        lower,
+        theme_dir.join("src"),
+        root.clone(),
+    ));
+    config.theme = Some(Theme {
+        name,
+        path: path.to_path_buf(),
+        config_path,
+        root,
+    });
+    Ok(())
+}
+
+/// The directory of `archive` holding `theme.toml`: its top, or the one directory
+/// archives made with a tool like `git archive --prefix` put everything in.
+fn archive_root(archive: &ArchiveFs) -> PathBuf {
+    if archive.is_file(Path::new(THEME_FILE_NAME)) {
+        return PathBuf::new();
+    }
+    match archive.read_dir(Path::new("")).as_deref() {
+        Ok([dir]) if archive.is_file(&dir.join(THEME_FILE_NAME)) => dir.clone(),
+        _ => PathBuf::new(),
+    }
+}
@@ -0,0 +1,1088 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where a build reads its sources from or writes its output to. Every file access of a
+/// build goes through one of these, so a site can be built from memory or an archive,
+/// and into memory, as well as on disk.
+pub trait FileSystem: Send + Sync {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>>;
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    /// The entries of the directory `path`, each joined onto `path`, in no particular
+    /// order.
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>>;
+
+    /// A path that identifies the existing file or directory at `path`, however it was
+    /// spelled.
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf>;
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()>;
+
+    /// Removes the directory `path` if it is empty.
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+
+    /// Swaps the files or directories at `a` and `b` in one step, so there is no moment
+    /// at which either path is missing. Fails with `Unsupported` where that can't be done
+    /// atomically.
+    fn exchange(&self, a: &Path, b: &Path) -> std::io::Result<()> {
+        let _ = (a, b);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Atomic exchange is not supported",
+        ))
+    }
+
+    /// Makes `to` a second name for the file `from`, or a copy of it where that isn't
+    /// supported.
+    fn hard_link(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        let contents = self.read(from)?;
+        self.write(to, &contents)
+    }
+}
+
+/// Lets a caller keep a handle on a filesystem it hands to a site, e.g. to look at a
+/// [`MemoryFs`] after building into it.
+impl<T: FileSystem + ?Sized> FileSystem for Arc<T> {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        (**self).read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        (**self).read_to_string(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        (**self).is_file(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        (**self).is_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        (**self).exists(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        (**self).read_dir(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        (**self).canonicalize(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        (**self).write(path, contents)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        (**self).create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        (**self).remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        (**self).remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        (**self).remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn exchange(&self, a: &Path, b: &Path) -> std::io::Result<()> {
+        (**self).exchange(a, b)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        (**self).hard_link(from, to)
+    }
+}
+
+/// The real filesystem.
+#[derive(Default, Clone, Copy)]
+pub struct DiskFs;
+
+impl FileSystem for DiskFs {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn exchange(&self, a: &Path, b: &Path) -> std::io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let a = CString::new(a.as_os_str().as_bytes())?;
+        let b = CString::new(b.as_os_str().as_bytes())?;
+        // `renameat2` is called through `syscall` because older C libraries don't wrap it.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                libc::AT_FDCWD,
+                a.as_ptr(),
+                libc::AT_FDCWD,
+                b.as_ptr(),
+                libc::RENAME_EXCHANGE,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => {
+                let error = std::io::Error::last_os_error();
+                match error.raw_os_error() {
+                    // Kernels and filesystems that don't know the flag.
+                    Some(libc::ENOSYS | libc::EINVAL) => {
+                        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, error))
+                    }
+                    _ => Err(error),
+                }
+            }
+        }
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        if fs::hard_link(from, to).is_err() {
+            fs::copy(from, to)?;
+        }
+        Ok(())
+    }
+}
+
+/// A filesystem held in memory. Paths are compared after resolving `.` and `..`, and
+/// relative paths are taken to be relative to the root, so `./src/a.html` and
+/// `/src/a.html` name the same file.
+#[derive(Default)]
+pub struct MemoryFs {
+    tree: Mutex<Tree>,
+}
+
+/// What [`Tree::take`] removed, relative to where it was.
+#[derive(Default)]
+struct Subtree {
+    files: Vec<(PathBuf, Arc<Vec<u8>>)>,
+    dirs: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct Tree {
+    /// Shared so hard links don't copy.
+    files: BTreeMap<PathBuf, Arc<Vec<u8>>>,
+    /// Every directory but the root.
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Adds a file, creating the directories above it.
+    pub fn insert(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        let path = normalize(path.as_ref());
+        let mut tree = self.tree.lock().unwrap();
+        tree.add_parents(&path);
+        tree.files.insert(path, Arc::new(contents.into()));
+    }
+
+    /// Every file, by its path from the root.
+    pub fn files(&self) -> BTreeMap<PathBuf, Vec<u8>> {
+        self.tree
+            .lock()
+            .unwrap()
+            .files
+            .iter()
+            .map(|(path, contents)| (path.clone(), contents.to_vec()))
+            .collect()
+    }
+}
+
+impl Tree {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.dirs.contains(path)
+    }
+
+    fn add_parents(&mut self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(current) = dir {
+            if current.as_os_str().is_empty() {
+                break;
+            }
+            self.dirs.insert(current.to_path_buf());
+            dir = current.parent();
+        }
+    }
+
+    fn check_parent(&self, path: &Path) -> std::io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(not_found(parent)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `path` is a file or a directory.
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path) || self.is_dir(path)
+    }
+
+    /// Removes the file or directory tree at `path`, returning its entries relative to
+    /// `path` for [`Tree::put`]. The directory itself is the entry with an empty path.
+    fn take(&mut self, path: &Path) -> Subtree {
+        let mut subtree = Subtree::default();
+        if let Some(contents) = self.files.remove(path) {
+            subtree.files.push((PathBuf::new(), contents));
+            return subtree;
+        }
+        let (files, dirs) = self.descendants(path);
+        for file in files {
+            let contents = self.files.remove(&file).unwrap();
+            subtree
+                .files
+                .push((file.strip_prefix(path).unwrap().to_path_buf(), contents));
+        }
+        for dir in dirs {
+            self.dirs.remove(&dir);
+            subtree
+                .dirs
+                .push(dir.strip_prefix(path).unwrap().to_path_buf());
+        }
+        self.dirs.remove(path);
+        subtree.dirs.push(PathBuf::new());
+        subtree
+    }
+
+    fn put(&mut self, path: &Path, subtree: Subtree) {
+        for dir in subtree.dirs {
+            self.dirs.insert(path.join(dir));
+        }
+        for (file, contents) in subtree.files {
+            let target = if file.as_os_str().is_empty() {
+                path.to_path_buf()
+            } else {
+                path.join(file)
+            };
+            self.files.insert(target, contents);
+        }
+    }
+
+    /// Paths of the files and directories under the directory `path`.
+    fn descendants(&self, path: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let under = |entry: &&PathBuf| entry.starts_with(path) && entry.as_path() != path;
+        (
+            self.files.keys().filter(under).cloned().collect(),
+            self.dirs.iter().filter(under).cloned().collect(),
+        )
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        match self.tree.lock().unwrap().files.get(&normalize(path)) {
+            Some(contents) => Ok(contents.to_vec()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.tree
+            .lock()
+            .unwrap()
+            .files
+            .contains_key(&normalize(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.tree.lock().unwrap().is_dir(&normalize(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let normalized = normalize(path);
+        let tree = self.tree.lock().unwrap();
+        if !tree.is_dir(&normalized) {
+            return Err(not_found(path));
+        }
+        Ok(tree
+            .files
+            .keys()
+            .chain(&tree.dirs)
+            .filter(|entry| entry.parent() == Some(normalized.as_path()))
+            .map(|entry| path.join(entry.file_name().unwrap()))
+            .collect())
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        if !self.exists(path) {
+            return Err(not_found(path));
+        }
+        Ok(Path::new("/").join(normalize(path)))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        let normalized = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        tree.check_parent(&normalized)?;
+        if tree.is_dir(&normalized) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::IsADirectory,
+                format!("{:?} is a directory", path),
+            ));
+        }
+        tree.files.insert(normalized, Arc::new(contents.to_vec()));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        let normalized = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        if tree.files.contains_key(&normalized) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{:?} is a file", path),
+            ));
+        }
+        tree.add_parents(&normalized);
+        if !normalized.as_os_str().is_empty() {
+            tree.dirs.insert(normalized);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        match self.tree.lock().unwrap().files.remove(&normalize(path)) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        let normalized = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        if !tree.dirs.contains(&normalized) {
+            return Err(not_found(path));
+        }
+        let (files, dirs) = tree.descendants(&normalized);
+        if !files.is_empty() || !dirs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::DirectoryNotEmpty,
+                format!("{:?} is not empty", path),
+            ));
+        }
+        tree.dirs.remove(&normalized);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        let normalized = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        if !tree.dirs.contains(&normalized) {
+            return Err(not_found(path));
+        }
+        let (files, dirs) = tree.descendants(&normalized);
+        for file in files {
+            tree.files.remove(&file);
+        }
+        for dir in dirs {
+            tree.dirs.remove(&dir);
+        }
+        tree.dirs.remove(&normalized);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut tree = self.tree.lock().unwrap();
+        tree.check_parent(&to)?;
+        if let Some(contents) = tree.files.remove(&from) {
+            tree.files.insert(to, contents);
+            return Ok(());
+        }
+        if !tree.dirs.contains(&from) {
+            return Err(not_found(&from));
+        }
+        if tree.files.contains_key(&to) || tree.dirs.contains(&to) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", to),
+            ));
+        }
+        let (files, dirs) = tree.descendants(&from);
+        for file in files {
+            let contents = tree.files.remove(&file).unwrap();
+            tree.files
+                .insert(to.join(file.strip_prefix(&from).unwrap()), contents);
+        }
+        for dir in dirs {
+            tree.dirs.remove(&dir);
+            tree.dirs.insert(to.join(dir.strip_prefix(&from).unwrap()));
+        }
+        tree.dirs.remove(&from);
+        tree.dirs.insert(to);
+        Ok(())
+    }
+
+    fn exchange(&self, a: &Path, b: &Path) -> std::io::Result<()> {
+        let (a, b) = (normalize(a), normalize(b));
+        let mut tree = self.tree.lock().unwrap();
+        if a.starts_with(&b) || b.starts_with(&a) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Can't exchange {:?} with {:?}", a, b),
+            ));
+        }
+        if !tree.exists(&a) {
+            return Err(not_found(&a));
+        }
+        if !tree.exists(&b) {
+            return Err(not_found(&b));
+        }
+        let moved_a = tree.take(&a);
+        let moved_b = tree.take(&b);
+        tree.put(&b, moved_a);
+        tree.put(&a, moved_b);
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut tree = self.tree.lock().unwrap();
+        tree.check_parent(&to)?;
+        let Some(contents) = tree.files.get(&from).cloned() else {
+            return Err(not_found(&from));
+        };
+        tree.files.insert(to, contents);
+        Ok(())
+    }
+}
+
+/// The files of a tar (optionally gzipped) or zip archive, such as the output of
+/// `git archive`. Read into memory when opened; writing to it fails.
+pub struct ArchiveFs {
+    files: MemoryFs,
+}
+
+impl ArchiveFs {
+    /// Opens the archive at `path` on disk, telling zip from tar by its contents.
+    pub fn open(path: &Path) -> std::io::Result<ArchiveFs> {
+        ArchiveFs::from_bytes(fs::read(path)?)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("Failed to read {:?}: {}", path, e)))
+    }
+
+    /// Reads a tar, tar.gz or zip archive held in memory.
+    pub fn from_bytes(bytes: Vec<u8>) -> std::io::Result<ArchiveFs> {
+        if bytes.starts_with(b"PK\x03\x04") {
+            ArchiveFs::from_zip(std::io::Cursor::new(bytes))
+        } else {
+            ArchiveFs::from_tar(bytes.as_slice())
+        }
+    }
+
+    /// Reads a tar archive, gunzipping it first if it is compressed. Links and special
+    /// files are left out.
+    pub fn from_tar(mut reader: impl Read) -> std::io::Result<ArchiveFs> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+            bytes = decompressed;
+        }
+
+        let files = MemoryFs::new();
+        for entry in tar::Archive::new(bytes.as_slice()).entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let mut contents = Vec::new();
+                    entry.read_to_end(&mut contents)?;
+                    files.insert(&path, contents);
+                }
+                tar::EntryType::Directory => files.create_dir_all(&path)?,
+                _ => {}
+            }
+        }
+        Ok(ArchiveFs { files })
+    }
+
+    pub fn from_zip(reader: impl Read + Seek) -> std::io::Result<ArchiveFs> {
+        let mut archive = zip::ZipArchive::new(reader).map_err(std::io::Error::other)?;
+        let files = MemoryFs::new();
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(std::io::Error::other)?;
+            let Some(path) = entry.enclosed_name() else {
+                continue;
+            };
+            if entry.is_dir() {
+                files.create_dir_all(&path)?;
+            } else if entry.is_file() {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                files.insert(&path, contents);
+            }
+        }
+        Ok(ArchiveFs { files })
+    }
+}
+
+impl FileSystem for ArchiveFs {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        self.files.read(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.is_file(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files.is_dir(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        self.files.read_dir(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        self.files.canonicalize(path)
+    }
+
+    fn write(&self, path: &Path, _contents: &[u8]) -> std::io::Result<()> {
+        Err(read_only(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        Err(read_only(path))
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        Err(read_only(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        Err(read_only(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        Err(read_only(path))
+    }
+
+    fn rename(&self, from: &Path, _to: &Path) -> std::io::Result<()> {
+        Err(read_only(from))
+    }
+}
+
+/// One filesystem laid over another below a mount point: under `upper_dir`, a path that
+/// doesn't exist in `upper` is looked up at the same relative path under `lower_dir` in
+/// `lower`. Files only found in `lower` are canonicalized to paths under
+/// `lower_canonical`, which lead back to them, so they can be told apart from the files
+/// of `upper`. Everything else, and every write, goes to `upper`.
+pub struct OverlayFs {
+    upper: Arc<dyn FileSystem>,
+    upper_dir: PathBuf,
+    lower: Arc<dyn FileSystem>,
+    lower_dir: PathBuf,
+    lower_canonical: PathBuf,
+}
+
+impl OverlayFs {
+    pub fn new(
+        upper: Arc<dyn FileSystem>,
+        upper_dir: PathBuf,
+        lower: Arc<dyn FileSystem>,
+        lower_dir: PathBuf,
+        lower_canonical: PathBuf,
+    ) -> OverlayFs {
+        OverlayFs {
+            upper,
+            upper_dir,
+            lower,
+            lower_dir,
+            lower_canonical,
+        }
+    }
+
+    /// Where `path` is in `lower`, if the overlay may look for it there.
+    fn lower_path(&self, path: &Path) -> Option<PathBuf> {
+        if let Ok(relative) = path.strip_prefix(&self.lower_canonical) {
+            return Some(self.lower_dir.join(relative));
+        }
+        match path.strip_prefix(&self.upper_dir) {
+            Ok(relative) if !self.upper.exists(path) => Some(self.lower_dir.join(relative)),
+            _ => None,
+        }
+    }
+}
+
+impl FileSystem for OverlayFs {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        match self.lower_path(path) {
+            Some(lower_path) => self.lower.read(&lower_path),
+            None => self.upper.read(path),
+        }
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        match self.lower_path(path) {
+            Some(lower_path) => self.lower.is_file(&lower_path),
+            None => self.upper.is_file(path),
+        }
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        match self.lower_path(path) {
+            Some(lower_path) => self.lower.is_dir(&lower_path),
+            None => self.upper.is_dir(path),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let Ok(relative) = path.strip_prefix(&self.upper_dir) else {
+            return match self.lower_path(path) {
+                Some(lower_path) => self.lower.read_dir(&lower_path),
+                None => self.upper.read_dir(path),
+            };
+        };
+        let lower_dir = self.lower_dir.join(relative);
+        if !self.upper.is_dir(path) {
+            if !self.lower.is_dir(&lower_dir) {
+                return Err(not_found(path));
+            }
+            return Ok(self
+                .lower
+                .read_dir(&lower_dir)?
+                .into_iter()
+                .map(|entry| path.join(entry.file_name().unwrap()))
+                .collect());
+        }
+        let mut entries = self.upper.read_dir(path)?;
+        if self.lower.is_dir(&lower_dir) {
+            let names: BTreeSet<_> = entries
+                .iter()
+                .filter_map(|entry| entry.file_name().map(|name| name.to_os_string()))
+                .collect();
+            for entry in self.lower.read_dir(&lower_dir)? {
+                let name = entry.file_name().unwrap();
+                if !names.contains(name) {
+                    entries.push(path.join(name));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let Some(lower_path) = self.lower_path(path) else {
+            return self.upper.canonicalize(path);
+        };
+        let canonical = self.lower.canonicalize(&lower_path)?;
+        let root = self.lower.canonicalize(&self.lower_dir)?;
+        match canonical.strip_prefix(&root) {
+            Ok(relative) => Ok(self.lower_canonical.join(relative)),
+            Err(_) => Ok(canonical),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        self.upper.write(path, contents)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        self.upper.create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        self.upper.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        self.upper.remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        self.upper.remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.upper.rename(from, to)
+    }
+
+    fn exchange(&self, a: &Path, b: &Path) -> std::io::Result<()> {
+        self.upper.exchange(a, b)
+    }
+}
+
+/// Writes the files under `dir` in `fs` to a zip archive, named relative to `dir`.
+/// Entries are sorted and undated, so the same files always make the same archive.
+pub fn write_zip(
+    fs: &dyn FileSystem,
+    dir: &Path,
+    skip: impl Fn(&Path) -> bool,
+    writer: impl Write + Seek,
+) -> std::io::Result<()> {
+    let mut files = Vec::new();
+    collect_files(fs, dir, &mut files)?;
+    files.sort();
+
+    let mut zip = zip::ZipWriter::new(writer);
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    for path in files {
+        let relative = path.strip_prefix(dir).unwrap();
+        if skip(relative) {
+            continue;
+        }
+        let name: Vec<String> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        zip.start_file(name.join("/"), options)
+            .map_err(std::io::Error::other)?;
+        zip.write_all(&fs.read(&path)?)?;
+    }
+    zip.finish().map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+fn collect_files(fs: &dyn FileSystem, dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for path in fs.read_dir(dir)? {
+        if fs.is_dir(&path) {
+            collect_files(fs, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// `path` relative to the root with `.` and `..` resolved.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
+
+fn read_only(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!("Can't write {:?}: archives are read-only", path),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(fs: &dyn FileSystem, path: &str) -> String {
+        fs.read_to_string(Path::new(path)).unwrap()
+    }
+
+    fn site_files() -> MemoryFs {
+        let fs = MemoryFs::new();
+        fs.insert("site/index.html", "<p>Home</p>");
+        fs.insert("site/blog/post.md", "# Post");
+        fs.insert("site/blog/images/a.png", [0u8, 159, 146, 150]);
+        fs
+    }
+
+    #[test]
+    fn memory_paths_are_normalized() {
+        let fs = site_files();
+        assert!(fs.is_file(Path::new("./site/blog/../index.html")));
+        assert!(fs.is_file(Path::new("/site/index.html")));
+        assert!(fs.is_dir(Path::new("site/blog/images")));
+        assert_eq!(
+            fs.canonicalize(Path::new("site/./blog/post.md")).unwrap(),
+            Path::new("/site/blog/post.md")
+        );
+        let mut entries = fs.read_dir(Path::new("./site/blog")).unwrap();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                PathBuf::from("./site/blog/images"),
+                PathBuf::from("./site/blog/post.md")
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_write_needs_parent_directory() {
+        let fs = MemoryFs::new();
+        let error = fs.write(Path::new("out/a.html"), b"a").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        fs.create_dir_all(Path::new("out")).unwrap();
+        fs.write(Path::new("out/a.html"), b"a").unwrap();
+        assert_eq!(read(&fs, "out/a.html"), "a");
+    }
+
+    #[test]
+    fn memory_rename_moves_files_and_trees() {
+        let fs = site_files();
+        fs.rename(Path::new("site/index.html"), Path::new("site/home.html"))
+            .unwrap();
+        assert!(!fs.exists(Path::new("site/index.html")));
+        assert_eq!(read(&fs, "site/home.html"), "<p>Home</p>");
+
+        fs.rename(Path::new("site/blog"), Path::new("site/news"))
+            .unwrap();
+        assert!(!fs.exists(Path::new("site/blog")));
+        assert!(fs.is_dir(Path::new("site/news/images")));
+        assert_eq!(read(&fs, "site/news/post.md"), "# Post");
+
+        let error = fs
+            .rename(Path::new("site/news"), Path::new("missing/news"))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        fs.create_dir_all(Path::new("site/taken")).unwrap();
+        let error = fs
+            .rename(Path::new("site/news"), Path::new("site/taken"))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn memory_hard_links_survive_replacing_either_name() {
+        let fs = site_files();
+        fs.hard_link(Path::new("site/index.html"), Path::new("site/copy.html"))
+            .unwrap();
+        assert_eq!(read(&fs, "site/copy.html"), "<p>Home</p>");
+
+        // The way staging replaces a linked file: remove it, then write a new one.
+        fs.remove_file(Path::new("site/copy.html")).unwrap();
+        fs.write(Path::new("site/copy.html"), b"<p>New</p>")
+            .unwrap();
+        assert_eq!(read(&fs, "site/index.html"), "<p>Home</p>");
+        assert_eq!(read(&fs, "site/copy.html"), "<p>New</p>");
+
+        fs.remove_file(Path::new("site/index.html")).unwrap();
+        assert_eq!(read(&fs, "site/copy.html"), "<p>New</p>");
+    }
+
+    #[test]
+    fn memory_exchange_swaps_trees() {
+        let fs = site_files();
+        fs.insert("other/only.txt", "only");
+        fs.exchange(Path::new("site"), Path::new("other")).unwrap();
+        assert_eq!(read(&fs, "site/only.txt"), "only");
+        assert!(!fs.exists(Path::new("site/index.html")));
+        assert_eq!(read(&fs, "other/index.html"), "<p>Home</p>");
+        assert!(fs.is_dir(Path::new("other/blog/images")));
+        assert!(fs
+            .exchange(Path::new("site"), Path::new("site/only.txt"))
+            .is_err());
+    }
+
+    #[test]
+    fn memory_remove_dir_all_takes_everything_below() {
+        let fs = site_files();
+        assert_eq!(
+            fs.remove_dir(Path::new("site/blog")).unwrap_err().kind(),
+            std::io::ErrorKind::DirectoryNotEmpty
+        );
+        fs.remove_dir_all(Path::new("site/blog")).unwrap();
+        assert!(!fs.exists(Path::new("site/blog/images/a.png")));
+        assert!(!fs.exists(Path::new("site/blog/images")));
+        assert!(fs.is_file(Path::new("site/index.html")));
+    }
+
+    fn tar_of(fs: &MemoryFs) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in fs.files() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, &path, contents.as_slice())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn assert_same_files(archive: &ArchiveFs, expected: &MemoryFs) {
+        for (path, contents) in expected.files() {
+            assert_eq!(archive.read(&path).unwrap(), contents, "{:?}", path);
+        }
+        assert!(archive.is_dir(Path::new("site/blog/images")));
+    }
+
+    #[test]
+    fn archive_reads_tar_and_tar_gz() {
+        let fs = site_files();
+        let tar = tar_of(&fs);
+        assert_same_files(&ArchiveFs::from_bytes(tar.clone()).unwrap(), &fs);
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&tar).unwrap();
+        let tar_gz = encoder.finish().unwrap();
+        assert_same_files(&ArchiveFs::from_bytes(tar_gz).unwrap(), &fs);
+    }
+
+    #[test]
+    fn archive_zip_round_trip() {
+        let fs = site_files();
+        let mut zip = Cursor::new(Vec::new());
+        write_zip(&fs, Path::new("."), |_| false, &mut zip).unwrap();
+        let archive = ArchiveFs::from_bytes(zip.into_inner()).unwrap();
+        assert_same_files(&archive, &fs);
+    }
+
+    #[test]
+    fn write_zip_skips_and_strips_the_directory() {
+        let fs = site_files();
+        let mut zip = Cursor::new(Vec::new());
+        write_zip(
+            &fs,
+            Path::new("site"),
+            |path| path.starts_with("blog/images"),
+            &mut zip,
+        )
+        .unwrap();
+        let archive = ArchiveFs::from_zip(zip).unwrap();
+        assert_eq!(
+            archive.files.files().into_keys().collect::<Vec<_>>(),
+            [PathBuf::from("blog/post.md"), PathBuf::from("index.html")]
+        );
+    }
+
+    #[test]
+    fn archive_is_read_only() {
+        let archive = ArchiveFs::from_bytes(tar_of(&site_files())).unwrap();
+        let error = archive
+            .write(Path::new("site/index.html"), b"changed")
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(read(&archive, "site/index.html"), "<p>Home</p>");
+    }
+
+    #[test]
+    fn overlay_prefers_upper_and_falls_back_to_lower() {
+        let upper = MemoryFs::new();
+        upper.insert("src/index.html", "site index");
+        upper.insert("src/css/site.css", "site css");
+        let lower = MemoryFs::new();
+        lower.insert("theme/src/index.html", "theme index");
+        lower.insert("theme/src/_layout.html", "theme layout");
+        lower.insert("theme/src/css/theme.css", "theme css");
+        let overlay = OverlayFs::new(
+            Arc::new(upper),
+            PathBuf::from("src"),
+            Arc::new(lower),
+            PathBuf::from("theme/src"),
+            PathBuf::from("/themes/plain/src"),
+        );
+
+        assert_eq!(read(&overlay, "src/index.html"), "site index");
+        assert_eq!(read(&overlay, "src/_layout.html"), "theme layout");
+        let mut entries = overlay.read_dir(Path::new("src/css")).unwrap();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                PathBuf::from("src/css/site.css"),
+                PathBuf::from("src/css/theme.css")
+            ]
+        );
+
+        let canonical = overlay.canonicalize(Path::new("src/_layout.html")).unwrap();
+        assert_eq!(canonical, Path::new("/themes/plain/src/_layout.html"));
+        assert_eq!(read(&overlay, canonical.to_str().unwrap()), "theme layout");
+        assert_eq!(
+            overlay.canonicalize(Path::new("src/index.html")).unwrap(),
+            Path::new("/src/index.html")
+        );
+    }
+}
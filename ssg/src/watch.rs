@@ -0,0 +1,271 @@
+use crate::config::{SiteConfig, Verbosity};
+use crate::site::{BuildReport, Site};
+use crate::staging;
+use notify::event::ModifyKind;
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+/// How long the sources have to stay quiet before a burst of changes is built, so an
+/// editor's save (write, rename, chmod...) turns into one rebuild that sees all of it.
+const DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// How often to look for the source directory again after it was deleted.
+const RECOVERY_POLL: Duration = Duration::from_millis(500);
+
+/// A batch of changes, collected until the inputs went quiet.
+#[derive(Default)]
+struct Changes {
+    paths: BTreeSet<PathBuf>,
+    /// The source directory itself was deleted or moved away, taking the watch with it.
+    source_dir_removed: bool,
+    config_changed: bool,
+}
+
+/// What is being watched: the source directory, plus the directories holding every
+/// other file the last build read or looked for, and the config files.
+struct Watches {
+    watcher: RecommendedWatcher,
+    source_dir: PathBuf,
+    /// The config file and the theme's, whose changes reload the settings.
+    config_paths: Vec<PathBuf>,
+    /// Directories watched on their own, outside the source directory.
+    dirs: BTreeSet<PathBuf>,
+    /// Files outside the source directory that affect the build.
+    inputs: BTreeSet<PathBuf>,
+    /// Paths the build itself writes to.
+    ignored: Vec<PathBuf>,
+}
+
+/// Rebuilds the outputs of `site` affected by each burst of changes to its inputs,
+/// printing and handing each build's result to `on_build`. A change to the config file
+/// or the theme's reloads the settings through `load_config` and rebuilds everything.
+pub fn watch_and_generate(
+    mut site: Site,
+    load_config: impl Fn() -> std::io::Result<SiteConfig>,
+    mut on_build: impl FnMut(&std::io::Result<BuildReport>),
+) -> std::io::Result<()> {
+    let (tx, rx) = channel();
+    let watcher = RecommendedWatcher::new(tx, Config::default()).map_err(std::io::Error::other)?;
+    let mut watches = Watches::new(watcher, site.config())?;
+    watches.follow(&site);
+
+    loop {
+        let changes = next_changes(&rx, &watches)?;
+        if site.config().verbosity >= Verbosity::Verbose {
+            for path in &changes.paths {
+                println!("Changed: {:?}", path);
+            }
+        }
+
+        let result = if changes.config_changed {
+            load_config().and_then(|config| {
+                if config.verbosity >= Verbosity::Normal {
+                    println!("Reloaded the settings");
+                }
+                watches.reset(&config)?;
+                site.set_config(config);
+                site.build()
+            })
+        } else if changes.source_dir_removed || !watches.source_dir.is_dir() {
+            if !watches.source_dir.is_dir() && site.config().verbosity >= Verbosity::Normal {
+                println!(
+                    "Source directory {:?} is gone, waiting for it to come back",
+                    site.config().source_dir
+                );
+            }
+            watches.rewatch_source_dir()?;
+            site.rebuild("the source directory was replaced")
+        } else {
+            let paths: Vec<PathBuf> = changes.paths.into_iter().collect();
+            site.update(&paths)
+        };
+        watches.follow(&site);
+
+        let result = result.and_then(|report| {
+            report.print(site.config())?;
+            Ok(report)
+        });
+        if let Err(e) = &result {
+            eprintln!("Error generating site: {}", e);
+        }
+        on_build(&result);
+    }
+}
+
+impl Watches {
+    fn new(watcher: RecommendedWatcher, config: &SiteConfig) -> std::io::Result<Watches> {
+        let mut watches = Watches {
+            watcher,
+            source_dir: PathBuf::new(),
+            config_paths: Vec::new(),
+            dirs: BTreeSet::new(),
+            inputs: BTreeSet::new(),
+            ignored: Vec::new(),
+        };
+        watches.reset(config)?;
+        Ok(watches)
+    }
+
+    /// Drops every watch and starts again from `config`.
+    fn reset(&mut self, config: &SiteConfig) -> std::io::Result<()> {
+        let _ = self.watcher.unwatch(&self.source_dir);
+        for dir in std::mem::take(&mut self.dirs) {
+            let _ = self.watcher.unwatch(&dir);
+        }
+        self.inputs.clear();
+
+        // Watching absolute paths makes notify report absolute paths, which is what the
+        // filters compare against.
+        self.source_dir = fs::canonicalize(&config.source_dir)?;
+        self.ignored = ignored_paths(config)?;
+        self.watcher
+            .watch(&self.source_dir, RecursiveMode::Recursive)
+            .map_err(std::io::Error::other)?;
+
+        self.config_paths.clear();
+        let theme_config = config.theme.as_ref().map(|theme| &theme.config_path);
+        for config_path in config.config_path.iter().chain(theme_config) {
+            let config_path = absolute(config_path)?;
+            self.config_paths.push(config_path.clone());
+            self.watch_input(config_path);
+        }
+        Ok(())
+    }
+
+    /// Waits for the source directory to exist again and watches the new one.
+    fn rewatch_source_dir(&mut self) -> std::io::Result<()> {
+        let _ = self.watcher.unwatch(&self.source_dir);
+        while !self.source_dir.is_dir() {
+            std::thread::sleep(RECOVERY_POLL);
+        }
+        self.watcher
+            .watch(&self.source_dir, RecursiveMode::Recursive)
+            .map_err(std::io::Error::other)
+    }
+
+    /// Starts watching the inputs of `site` that live outside the source directory.
+    fn follow(&mut self, site: &Site) {
+        for input in site.inputs() {
+            if let Ok(input) = absolute(&input) {
+                if !input.starts_with(&self.source_dir) {
+                    self.watch_input(input);
+                }
+            }
+        }
+    }
+
+    /// Watches the directory holding `input` rather than the file itself, so editors
+    /// that save by replacing the file don't end the watch.
+    fn watch_input(&mut self, input: PathBuf) {
+        if let Some(dir) = input.parent() {
+            if !self.dirs.contains(dir)
+                && self.watcher.watch(dir, RecursiveMode::NonRecursive).is_ok()
+            {
+                self.dirs.insert(dir.to_path_buf());
+            }
+        }
+        self.inputs.insert(input);
+    }
+
+    fn is_relevant(&self, path: &Path) -> bool {
+        if self.ignored.iter().any(|ignored| path.starts_with(ignored)) || is_editor_temp(path) {
+            return false;
+        }
+        path.starts_with(&self.source_dir) || self.inputs.contains(path)
+    }
+}
+
+/// Waits for a relevant change, then keeps collecting until none has arrived for
+/// `DEBOUNCE`.
+fn next_changes(
+    rx: &Receiver<notify::Result<Event>>,
+    watches: &Watches,
+) -> std::io::Result<Changes> {
+    let mut changes = Changes::default();
+    loop {
+        let is_empty =
+            changes.paths.is_empty() && !changes.source_dir_removed && !changes.config_changed;
+        let received = if is_empty {
+            rx.recv().map_err(std::io::Error::other)?
+        } else {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) => return Ok(changes),
+                Err(e) => return Err(std::io::Error::other(e)),
+            }
+        };
+        let event = match received {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Watch error: {}", e);
+                continue;
+            }
+        };
+        if matches!(event.kind, EventKind::Access(_)) {
+            continue;
+        }
+        for path in event.paths {
+            if path == watches.source_dir {
+                changes.source_dir_removed |= matches!(
+                    event.kind,
+                    EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+                );
+            } else if watches.config_paths.contains(&path) {
+                changes.config_changed = true;
+            } else if watches.is_relevant(&path) {
+                changes.paths.insert(path);
+            }
+        }
+    }
+}
+
+/// `path` made absolute, with its directory canonicalized the way notify reports the
+/// paths of watched directories.
+fn absolute(path: &Path) -> std::io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => match fs::canonicalize(dir) {
+            Ok(dir) => Ok(dir.join(name)),
+            Err(_) => Ok(path),
+        },
+        _ => Ok(path),
+    }
+}
+
+/// Paths the build itself writes to, whose changes must not trigger another build.
+fn ignored_paths(config: &SiteConfig) -> std::io::Result<Vec<PathBuf>> {
+    let output_dir = match fs::canonicalize(&config.output_dir) {
+        Ok(output_dir) => output_dir,
+        Err(_) => std::path::absolute(&config.output_dir)?,
+    };
+    let mut ignored = staging::work_dirs(&output_dir);
+    ignored.push(output_dir);
+    if let Some(cache_file) = &config.cache_file {
+        ignored.push(absolute(cache_file)?);
+    }
+    Ok(ignored)
+}
+
+/// Swap, backup and temporary files editors and tools write next to the file being
+/// saved.
+fn is_editor_temp(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+    name.ends_with('~')
+        || name.starts_with(".#")
+        || (name.starts_with('#') && name.ends_with('#'))
+        || matches!(extension, "swp" | "swo" | "swx" | "swpx" | "tmp" | "crswap")
+        // Vim checks whether it can create files in the directory with this one.
+        || name == "4913"
+        || name.contains("___jb_")
+        || name.starts_with(".goutputstream-")
+        || name == ".DS_Store"
+        // `sed -i` writes to `sedXXXXXX` and renames it over the original.
+        || (name.len() == 9 && name.starts_with("sed") && !name.contains('.'))
+}